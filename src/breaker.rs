@@ -0,0 +1,148 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Circuit breaker settings for the delivery to the endpoint
+///
+/// After `failure_threshold` consecutive failed deliveries the circuit opens and payloads
+/// aren't sent (or even captured, unless there is a spool to put them in). Once the
+/// `cooldown` passes the circuit is half-open and the next payload is used as a probe,
+/// which closes the circuit if it gets delivered or opens it again if it doesn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    /// Consecutive failures after which the circuit opens
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a probe is allowed
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State of the circuit breaker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Payloads are delivered as usual
+    Closed,
+    /// Endpoint is failing, payloads aren't delivered
+    Open,
+    /// Cooldown has passed, next delivery will check if the endpoint recovered
+    HalfOpen,
+}
+
+struct Inner {
+    failures: u32,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+pub(crate) struct Breaker {
+    config: CircuitBreaker,
+    inner: Mutex<Inner>,
+}
+
+impl Breaker {
+    pub fn new(config: CircuitBreaker) -> Breaker {
+        Breaker {
+            config,
+            inner: Mutex::new(Inner {
+                failures: 0,
+                opened_at: None,
+                probing: false,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => CircuitState::Closed,
+            Some(_) if inner.probing => CircuitState::HalfOpen,
+            Some(opened_at) if opened_at.elapsed() >= self.config.cooldown => {
+                CircuitState::HalfOpen
+            }
+            Some(_) => CircuitState::Open,
+        }
+    }
+
+    /// Check if a delivery can be attempted, once the cooldown passes only a single
+    /// probe is let through until its result is recorded
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => true,
+            Some(_) if inner.probing => false,
+            Some(opened_at) if opened_at.elapsed() >= self.config.cooldown => {
+                inner.probing = true;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.opened_at.is_some() {
+            log::info!("Treblle endpoint recovered, circuit closed");
+        }
+
+        inner.failures = 0;
+        inner.opened_at = None;
+        inner.probing = false;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures = inner.failures.saturating_add(1);
+
+        if inner.probing || inner.failures >= self.config.failure_threshold {
+            if inner.opened_at.is_none() {
+                log::warn!(
+                    "Treblle endpoint failed {} times in a row, circuit opened",
+                    inner.failures
+                );
+            }
+
+            inner.opened_at = Some(Instant::now());
+            inner.probing = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Breaker, CircuitBreaker, CircuitState};
+    use std::time::Duration;
+
+    #[test]
+    fn opens_and_recovers_through_a_probe() {
+        let breaker = Breaker::new(CircuitBreaker {
+            failure_threshold: 2,
+            cooldown: Duration::from_millis(20),
+        });
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,346 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::error::PayloadError;
+use actix_web::web::Bytes;
+use futures::task::{Context, Poll};
+use futures::{FutureExt, Stream, StreamExt};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::error::{report, ErrorHandler, TreblleError};
+use crate::media::MediaType;
+use crate::multipart::MultipartScanner;
+
+/// What is captured from the request and response bodies
+#[derive(Debug, Clone)]
+pub(crate) struct CaptureConfig {
+    /// Content types of the request bodies that are captured
+    pub content_types: Vec<String>,
+    /// Longest text field of a multipart body that is captured, `0` captures none
+    pub multipart_text: usize,
+    /// Number of bytes of the other request bodies that are copied while the handler
+    /// reads them
+    pub max_bytes: usize,
+    /// Number of bytes of the response bodies that stream which are copied while they
+    /// are sent, only bodies of the `STREAMED_TEXT_TYPES` are copied
+    pub response_max_bytes: usize,
+}
+
+/// Content types of the response bodies that stream which are captured, others like
+/// files and binary downloads are recorded with their size only
+pub(crate) const STREAMED_TEXT_TYPES: [&str; 3] = ["application/json", "+json", "text/*"];
+
+impl Default for CaptureConfig {
+    fn default() -> CaptureConfig {
+        CaptureConfig {
+            content_types: vec![
+                "application/json".to_string(),
+                "+json".to_string(),
+                "application/x-www-form-urlencoded".to_string(),
+                "multipart/form-data".to_string(),
+            ],
+            multipart_text: 0,
+            max_bytes: 1024 * 1024,
+            response_max_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// Capture of a request body that sees the chunks while the handler reads them
+pub(crate) enum BodyCapture {
+    Multipart(MultipartScanner),
+    Prefix(Prefix),
+}
+
+impl BodyCapture {
+    fn feed(&mut self, chunk: &[u8]) {
+        match self {
+            BodyCapture::Multipart(scanner) => scanner.feed(chunk),
+            BodyCapture::Prefix(prefix) => prefix.feed(chunk),
+        }
+    }
+
+    /// Body captured from the chunks the handler read, the parsing is left for after
+    /// the response so it doesn't delay the handler
+    pub fn finish(&self) -> Value {
+        match self {
+            BodyCapture::Multipart(scanner) => scanner.finish(),
+            BodyCapture::Prefix(prefix) => prefix.finish(),
+        }
+    }
+}
+
+/// Copy of the first bytes of a body, the bytes over the limit are only counted
+pub(crate) struct Prefix {
+    media_type: MediaType,
+    bytes: Vec<u8>,
+    max_bytes: usize,
+    size: u64,
+    /// Whether part of the body was never read
+    unread: bool,
+}
+
+impl Prefix {
+    pub fn new(media_type: MediaType, max_bytes: usize) -> Prefix {
+        Prefix {
+            media_type,
+            bytes: vec![],
+            max_bytes,
+            size: 0,
+            unread: false,
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) {
+        self.size += chunk.len() as u64;
+        let take = chunk.len().min(self.max_bytes - self.bytes.len());
+        self.bytes.extend_from_slice(&chunk[..take]);
+    }
+
+    fn truncated(&self) -> bool {
+        self.unread || self.size > self.bytes.len() as u64
+    }
+
+    fn finish(&self) -> Value {
+        let bytes = &self.bytes[..];
+        if bytes.is_empty() && !self.truncated() {
+            return Value::Null;
+        }
+
+        // Cut off bodies can't be parsed, they are kept as text together with the size
+        // that was read so the masking of the raw bodies still applies to them
+        if self.truncated() {
+            let mut map = Map::new();
+            map.insert(
+                "request_as_a_string".to_string(),
+                Value::String(String::from_utf8_lossy(bytes).into_owned()),
+            );
+            map.insert("truncated".to_string(), Value::Bool(true));
+            map.insert("size".to_string(), Value::from(self.size));
+
+            return Value::Object(map);
+        }
+
+        if self.media_type.matches("application/x-www-form-urlencoded") {
+            return parse_form(bytes);
+        }
+
+        match serde_json::from_slice::<Value>(bytes) {
+            Ok(v) => v,
+            Err(_) => match std::str::from_utf8(bytes) {
+                Ok(s) => {
+                    let mut map = Map::new();
+                    map.insert(
+                        "request_as_a_string".to_string(),
+                        Value::String(s.to_string()),
+                    );
+
+                    Value::Object(map)
+                }
+                Err(_) => {
+                    let mut map = Map::new();
+                    map.insert(
+                        "request_as_raw_bytes".to_string(),
+                        Value::String(format!("{:?}", Bytes::copy_from_slice(bytes))),
+                    );
+
+                    Value::Object(map)
+                }
+            },
+        }
+    }
+}
+
+/// Parse the urlencoded form into a JSON object, values of the keys that are repeated
+/// are collected into arrays
+fn parse_form(bytes: &[u8]) -> Value {
+    let mut map = Map::new();
+    for (key, value) in form_urlencoded::parse(bytes) {
+        let value = Value::String(value.into_owned());
+        match map.get_mut(key.as_ref()) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                map.insert(key.into_owned(), value);
+            }
+        }
+    }
+
+    Value::Object(map)
+}
+
+/// Capture of the request body that is shared by the tee and the middleware
+pub(crate) struct RequestCapture<S> {
+    body: Rc<RefCell<BodyCapture>>,
+    unread: Rc<RefCell<Option<S>>>,
+    on_error: Option<ErrorHandler>,
+}
+
+impl<S> RequestCapture<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    /// Wrap the payload so the capture sees every chunk the handler reads
+    pub fn tee(
+        body: BodyCapture,
+        payload: S,
+        on_error: Option<ErrorHandler>,
+    ) -> (RequestCapture<S>, Tee<S>) {
+        let capture = RequestCapture {
+            body: Rc::new(RefCell::new(body)),
+            unread: Rc::new(RefCell::new(None)),
+            on_error,
+        };
+        let tee = Tee {
+            inner: Some(payload),
+            capture: capture.body.clone(),
+            unread: capture.unread.clone(),
+            on_error: capture.on_error.clone(),
+        };
+
+        (capture, tee)
+    }
+
+    /// Copy at most `max_bytes` of the part of the body that the handler didn't read, as
+    /// far as it already arrived, then parse everything that was copied. The client is
+    /// never waited for, and multipart bodies are left as far as the handler read them.
+    pub fn finish(self, max_bytes: usize) -> Value {
+        let unread = self.unread.borrow_mut().take();
+        let mut body = self.body.borrow_mut();
+        if let (Some(mut payload), BodyCapture::Prefix(prefix)) = (unread, &mut *body) {
+            let mut read = 0;
+            prefix.unread = loop {
+                if read > max_bytes {
+                    break true;
+                }
+
+                match payload.next().now_or_never() {
+                    Some(Some(Ok(chunk))) => {
+                        read += chunk.len();
+                        prefix.feed(&chunk);
+                    }
+                    Some(Some(Err(e))) => {
+                        report(self.on_error.as_ref(), TreblleError::Capture(e.to_string()));
+                        break true;
+                    }
+                    Some(None) => break false,
+                    // Rest of the body didn't arrive yet
+                    None => break true,
+                }
+            };
+        }
+
+        body.finish()
+    }
+}
+
+/// Payload stream that hands every chunk to the handler unchanged and lets the capture
+/// see it on the way, the part of the stream the handler didn't read is left for the
+/// capture once the tee is dropped
+pub(crate) struct Tee<S> {
+    inner: Option<S>,
+    capture: Rc<RefCell<BodyCapture>>,
+    unread: Rc<RefCell<Option<S>>>,
+    on_error: Option<ErrorHandler>,
+}
+
+impl<S> Stream for Tee<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = match self.inner.as_mut() {
+            Some(inner) => Pin::new(inner).poll_next(cx),
+            None => return Poll::Ready(None),
+        };
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => self.capture.borrow_mut().feed(chunk),
+            Poll::Ready(Some(Err(e))) => {
+                report(self.on_error.as_ref(), TreblleError::Capture(e.to_string()));
+                self.inner = None;
+            }
+            Poll::Ready(None) => self.inner = None,
+            Poll::Pending => {}
+        }
+
+        poll
+    }
+}
+
+impl<S> Drop for Tee<S> {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            *self.unread.borrow_mut() = Some(inner);
+        }
+    }
+}
+
+/// Callback that gets the copied bytes of the response body and its full size
+type OnEnd = Box<dyn FnOnce(&[u8], u64)>;
+
+/// Response body that is sent unchanged while its first bytes are copied, once the body
+/// ends, fails or is dropped the copy is handed to the callback
+pub(crate) struct TeeBody {
+    inner: BoxBody,
+    prefix: Vec<u8>,
+    max_bytes: usize,
+    size: u64,
+    on_end: Option<OnEnd>,
+}
+
+impl TeeBody {
+    pub fn new<F>(inner: BoxBody, max_bytes: usize, on_end: F) -> TeeBody
+    where
+        F: FnOnce(&[u8], u64) + 'static,
+    {
+        TeeBody {
+            inner,
+            prefix: vec![],
+            max_bytes,
+            size: 0,
+            on_end: Some(Box::new(on_end)),
+        }
+    }
+
+    fn end(&mut self) {
+        if let Some(on_end) = self.on_end.take() {
+            on_end(&self.prefix, self.size);
+        }
+    }
+}
+
+impl MessageBody for TeeBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let this = &mut *self;
+                this.size += chunk.len() as u64;
+                let take = chunk.len().min(this.max_bytes - this.prefix.len());
+                this.prefix.extend_from_slice(&chunk[..take]);
+            }
+            Poll::Ready(_) => self.end(),
+            Poll::Pending => {}
+        }
+
+        poll
+    }
+}
+
+impl Drop for TeeBody {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
@@ -0,0 +1,63 @@
+use std::io;
+
+/// Content encoding used to compress payloads before they are sent
+///
+/// Each encoding is available behind the cargo feature of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// `Content-Encoding: gzip`
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// `Content-Encoding: zstd`
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// Value of the `Content-Encoding` header
+    pub fn content_encoding(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Compress the body with this encoding
+    #[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+    pub fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(body, 0),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "gzip"))]
+mod test {
+    use super::Compression;
+    use std::io::Read;
+
+    #[test]
+    fn gzip_round_trip() {
+        let body = br#"{"api_key":"api_key","project_id":"project_id"}"#.repeat(10);
+        let encoded = Compression::Gzip.encode(&body).unwrap();
+        assert!(encoded.len() < body.len());
+
+        let mut decoded = vec![];
+        flate2::read::GzDecoder::new(encoded.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+}
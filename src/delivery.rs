@@ -0,0 +1,1199 @@
+use futures::FutureExt;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::breaker::{Breaker, CircuitBreaker, CircuitState};
+use crate::error::{report, ErrorHandler, TreblleError};
+use crate::payload::TreblleData;
+use crate::spool::{Spool, SpoolStore};
+use crate::transport::{Transport, TransportError};
+
+/// How often the worker tries to drain the spool while there is nothing else to deliver
+const SPOOL_DRAIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What happens with a payload when the delivery queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the payload that was just captured
+    DropNewest,
+    /// Drop the oldest queued payload to make room for the new one
+    DropOldest,
+    /// Wait in the request handler until there is room in the queue
+    Block,
+}
+
+/// Limits for grouping payloads into a batch, a batch is sent as soon as any
+/// of the limits is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Batching {
+    /// Maximum number of payloads in a single batch
+    pub max_payloads: usize,
+    /// Maximum size of the serialized payloads in a single batch
+    pub max_bytes: usize,
+    /// Maximum time the first payload of a batch waits for the others
+    pub linger: Duration,
+}
+
+impl Default for Batching {
+    fn default() -> Batching {
+        Batching {
+            max_payloads: 100,
+            max_bytes: 1024 * 1024,
+            linger: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Retry policy for payloads that failed to be delivered because of a transient failure
+///
+/// Backoff grows exponentially with every attempt, capped at `max_backoff`, and a random
+/// delay up to that amount is picked so retries of many instances don't line up. When
+/// the endpoint responds with a `Retry-After` header its value is used instead, and when
+/// it asks to wait longer than `max_backoff` the payload isn't retried, it's spooled or
+/// counted as failed right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Maximum number of delivery attempts, including the first one
+    pub max_attempts: u32,
+    /// Backoff before the first retry
+    pub initial_backoff: Duration,
+    /// Upper limit of the backoff between attempts, a longer `Retry-After` stops the retries
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Retry {
+    /// Time to wait before the attempt that follows the given failed one (starting at 1),
+    /// `None` when the endpoint asked to wait longer than the backoff is allowed to take
+    fn backoff(&self, attempt: u32, error: &TransportError) -> Option<Duration> {
+        if let Some(retry_after) = error.retry_after() {
+            return (retry_after <= self.max_backoff).then_some(retry_after);
+        }
+
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+
+        Some(rand::thread_rng().gen_range(Duration::ZERO..=exponential))
+    }
+}
+
+/// Configuration of the delivery worker
+#[derive(Debug, Clone)]
+pub(crate) struct DeliveryConfig {
+    pub capacity: usize,
+    pub policy: DropPolicy,
+    pub batching: Option<Batching>,
+    pub retry: Option<Retry>,
+    pub spool: Option<Spool>,
+    pub breaker: Option<CircuitBreaker>,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> DeliveryConfig {
+        DeliveryConfig {
+            capacity: 1000,
+            policy: DropPolicy::DropNewest,
+            batching: None,
+            retry: None,
+            spool: None,
+            breaker: None,
+        }
+    }
+}
+
+/// Snapshot of the delivery counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeliveryStats {
+    /// Payloads currently waiting in the queue
+    pub queued: usize,
+    /// Payloads accepted into the queue
+    pub enqueued: u64,
+    /// Payloads dropped because the queue was full
+    pub dropped: u64,
+    /// Payloads the transport delivered successfully
+    pub delivered: u64,
+    /// Payloads that were written to the spool because the endpoint was unreachable
+    pub spooled: u64,
+    /// Payloads that were lost because the transport failed to deliver them
+    pub failed: u64,
+}
+
+/// Counters and signals shared between the dispatcher and its worker
+#[derive(Default)]
+struct Shared {
+    enqueued: AtomicU64,
+    dropped: AtomicU64,
+    delivered: AtomicU64,
+    spooled: AtomicU64,
+    failed: AtomicU64,
+    /// Payloads that are queued or being delivered at the moment
+    pending: AtomicU64,
+    /// Notified once there are no pending payloads
+    idle: Notify,
+    /// Notified when someone waits for the pending payloads, so the worker stops
+    /// waiting for the batch to fill up
+    flush: Notify,
+}
+
+impl Shared {
+    /// Mark payloads as no longer pending and wake up the ones waiting for the flush
+    fn done(&self, count: u64) {
+        if self.pending.fetch_sub(count, Ordering::AcqRel) == count {
+            self.idle.notify_waiters();
+        }
+    }
+}
+
+/// Payload that isn't captured yet but already counts as pending
+pub(crate) struct Reserved(Arc<Shared>);
+
+impl Drop for Reserved {
+    fn drop(&mut self) {
+        self.0.done(1);
+    }
+}
+
+/// Outcome of pushing an item into the queue
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Push<T> {
+    /// Item is in the queue
+    Accepted,
+    /// Item is in the queue, but the oldest one had to be dropped to make room for it
+    Replaced(T),
+    /// Item was rejected because the queue is full or closed
+    Rejected(T),
+}
+
+/// Bounded multi producer, single consumer queue with a configurable drop policy
+pub(crate) struct Queue<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    policy: DropPolicy,
+    readable: Notify,
+    writable: Notify,
+    closed: AtomicBool,
+}
+
+impl<T> Queue<T> {
+    pub fn new(capacity: usize, policy: DropPolicy) -> Queue<T> {
+        Queue {
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity.max(1),
+            policy,
+            readable: Notify::new(),
+            writable: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Push the item into the queue, if something had to be dropped because the queue
+    /// was full it will be returned back.
+    pub async fn push(&self, item: T) -> Push<T> {
+        loop {
+            // Registered before the checks so the wakeup from close isn't missed
+            let writable = self.writable.notified();
+            futures::pin_mut!(writable);
+            writable.as_mut().enable();
+
+            if self.closed.load(Ordering::Acquire) {
+                return Push::Rejected(item);
+            }
+
+            {
+                let mut items = self.items.lock().unwrap();
+                if items.len() < self.capacity {
+                    items.push_back(item);
+                    self.readable.notify_one();
+
+                    return Push::Accepted;
+                }
+
+                match self.policy {
+                    DropPolicy::DropNewest => return Push::Rejected(item),
+                    DropPolicy::DropOldest => {
+                        items.push_back(item);
+                        self.readable.notify_one();
+
+                        return match items.pop_front() {
+                            Some(oldest) => Push::Replaced(oldest),
+                            None => Push::Accepted,
+                        };
+                    }
+                    DropPolicy::Block => {}
+                }
+            }
+
+            writable.await;
+        }
+    }
+
+    /// Wait for the next item, `None` is returned once the queue is closed and empty
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            if let Some(item) = self.items.lock().unwrap().pop_front() {
+                self.writable.notify_one();
+
+                return Some(item);
+            }
+
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+
+            self.readable.notified().await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    /// Stop accepting new items and wake up everyone waiting on the queue, blocked
+    /// producers will give up and the consumer will finish once it drains the queue
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+}
+
+/// Bounded queue of payloads drained by a single background worker
+pub(crate) struct Dispatcher {
+    queue: Arc<Queue<TreblleData>>,
+    shared: Arc<Shared>,
+    transport: Arc<dyn Transport>,
+    breaker: Option<Arc<Breaker>>,
+    spooling: bool,
+}
+
+impl Dispatcher {
+    /// Start the worker on its own thread so it doesn't depend on the lifetime of any
+    /// of the actix workers
+    pub fn start(
+        transport: Arc<dyn Transport>,
+        config: DeliveryConfig,
+        on_error: Option<ErrorHandler>,
+    ) -> Dispatcher {
+        let queue = Arc::new(Queue::new(config.capacity, config.policy));
+        let shared = Arc::new(Shared::default());
+        let breaker = config
+            .breaker
+            .map(|breaker| Arc::new(Breaker::new(breaker)));
+        let spooling = config.spool.is_some();
+
+        let mut worker = Worker {
+            queue: queue.clone(),
+            shared: shared.clone(),
+            transport: transport.clone(),
+            breaker: breaker.clone(),
+            config,
+            spool: None,
+            on_error,
+        };
+        let spawned = std::thread::Builder::new()
+            .name("treblle-delivery".to_string())
+            .spawn(move || {
+                let runtime = match tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        log::error!("Treblle delivery worker couldn't start: {}", e);
+                        worker.queue.close();
+                        return;
+                    }
+                };
+
+                if let Some(spool) = worker.config.spool.clone() {
+                    match SpoolStore::open(spool) {
+                        Ok(spool) => worker.spool = Some(spool),
+                        Err(e) => {
+                            log::error!("Treblle spool couldn't be opened: {}", e);
+                            report(worker.on_error.as_ref(), TreblleError::Spool(e));
+                        }
+                    }
+                }
+
+                runtime.block_on(worker.run());
+            });
+
+        if let Err(e) = spawned {
+            log::error!("Treblle delivery worker couldn't start: {}", e);
+            queue.close();
+        }
+
+        Dispatcher {
+            queue,
+            shared,
+            transport,
+            breaker,
+            spooling,
+        }
+    }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        self.breaker
+            .as_ref()
+            .map(|breaker| breaker.state())
+            .unwrap_or(CircuitState::Closed)
+    }
+
+    /// Whether captured payloads have somewhere to go, while the circuit is open and
+    /// there is no spool there is no point in capturing them at all
+    pub fn accepts(&self) -> bool {
+        self.spooling || self.circuit_state() != CircuitState::Open
+    }
+
+    /// Queue the payload for delivery according to the drop policy
+    pub async fn enqueue(&self, data: TreblleData) {
+        // Counted upfront, the worker might be done with the payload before push returns
+        self.shared.pending.fetch_add(1, Ordering::AcqRel);
+
+        match self.queue.push(data).await {
+            Push::Accepted => {
+                self.shared.enqueued.fetch_add(1, Ordering::Relaxed);
+            }
+            Push::Replaced(_) => {
+                self.shared.enqueued.fetch_add(1, Ordering::Relaxed);
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                self.shared.done(1);
+                log::debug!("Treblle delivery queue is full, oldest payload dropped");
+            }
+            Push::Rejected(_) => {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                self.shared.done(1);
+                log::debug!("Treblle delivery queue is full, payload dropped");
+            }
+        }
+    }
+
+    /// Count a payload that will be enqueued later as pending, so flushing waits for it
+    /// until the returned guard is dropped
+    pub fn reserve(&self) -> Reserved {
+        self.shared.pending.fetch_add(1, Ordering::AcqRel);
+
+        Reserved(self.shared.clone())
+    }
+
+    /// Wait until every queued payload is delivered, spooled or given up on. Returns
+    /// `false` if that didn't happen within the timeout.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        let wait = async {
+            loop {
+                let idle = self.shared.idle.notified();
+                futures::pin_mut!(idle);
+                idle.as_mut().enable();
+
+                if self.shared.pending.load(Ordering::Acquire) == 0 {
+                    return;
+                }
+
+                self.shared.flush.notify_one();
+                idle.await;
+            }
+        };
+
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+
+    /// Stop accepting new payloads and wait for the queued ones like [`Dispatcher::flush`]
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.queue.close();
+
+        self.flush(timeout).await
+    }
+
+    pub fn transport(&self) -> &Arc<dyn Transport> {
+        &self.transport
+    }
+
+    pub fn stats(&self) -> DeliveryStats {
+        DeliveryStats {
+            queued: self.queue.len(),
+            enqueued: self.shared.enqueued.load(Ordering::Relaxed),
+            dropped: self.shared.dropped.load(Ordering::Relaxed),
+            delivered: self.shared.delivered.load(Ordering::Relaxed),
+            spooled: self.shared.spooled.load(Ordering::Relaxed),
+            failed: self.shared.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+/// Background side of the dispatcher that drains the queue
+struct Worker {
+    queue: Arc<Queue<TreblleData>>,
+    shared: Arc<Shared>,
+    transport: Arc<dyn Transport>,
+    breaker: Option<Arc<Breaker>>,
+    config: DeliveryConfig,
+    spool: Option<SpoolStore>,
+    on_error: Option<ErrorHandler>,
+}
+
+impl Worker {
+    /// Deliver queued payloads until the queue is closed
+    async fn run(mut self) {
+        self.drain_spool().await;
+
+        loop {
+            let next = if self.spool_pending() {
+                match tokio::time::timeout(SPOOL_DRAIN_INTERVAL, self.queue.pop()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        self.drain_spool().await;
+                        continue;
+                    }
+                }
+            } else {
+                self.queue.pop().await
+            };
+
+            let data = match next {
+                Some(data) => data,
+                None => break,
+            };
+
+            let batch = match self.config.batching {
+                Some(batching) => self.collect_batch(data, batching).await,
+                None => vec![data],
+            };
+
+            let count = batch.len() as u64;
+            let delivered = self.deliver(batch).await;
+            self.shared.done(count);
+
+            // Endpoint is reachable again, so it's a good time to send what was spooled
+            if delivered && self.spool_pending() {
+                self.drain_spool().await;
+            }
+        }
+    }
+
+    fn spool_pending(&self) -> bool {
+        self.spool
+            .as_ref()
+            .map(|spool| !spool.is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Keep taking payloads from the queue until one of the batching limits is reached
+    async fn collect_batch(&self, first: TreblleData, batching: Batching) -> Vec<TreblleData> {
+        let deadline = Instant::now() + batching.linger;
+        let mut bytes = serialized_size(&first);
+        let mut batch = vec![first];
+
+        while batch.len() < batching.max_payloads && bytes < batching.max_bytes {
+            // Payloads that are already queued are taken before reacting to the flush
+            let next = tokio::select! {
+                biased;
+                next = tokio::time::timeout_at(deadline, self.queue.pop()) => next,
+                _ = self.shared.flush.notified() => break,
+            };
+
+            match next {
+                Ok(Some(data)) => {
+                    bytes += serialized_size(&data);
+                    batch.push(data);
+                }
+                // Queue was closed or the linger time has passed
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        batch
+    }
+
+    /// Send the batch, the error comes with the number of payloads that were delivered
+    /// before the failure. A transport that panics fails the batch instead of taking the
+    /// worker down with it.
+    async fn send(&self, batch: &[TreblleData]) -> Result<(), (usize, TransportError)> {
+        let sent = AssertUnwindSafe(async {
+            match batch {
+                [data] => self.transport.send(data).await.map_err(|e| (0, e)),
+                _ => self.transport.send_batch(batch).await,
+            }
+        })
+        .catch_unwind()
+        .await;
+
+        match sent {
+            Ok(result) => result.map_err(|(sent, e)| (sent.min(batch.len()), e)),
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                log::error!("Treblle transport panicked: {}", message);
+
+                Err((
+                    0,
+                    TransportError::Other(format!("transport panicked: {}", message).into()),
+                ))
+            }
+        }
+    }
+
+    /// Send the batch and spool what wasn't delivered if the endpoint is unreachable,
+    /// returns whether the whole batch was delivered
+    async fn deliver(&mut self, batch: Vec<TreblleData>) -> bool {
+        let result = match self.allow() {
+            true => Some(self.send_with_retry(&batch).await),
+            false => None,
+        };
+
+        // No error means the circuit is open and the endpoint wasn't even tried
+        let (unreachable, error, batch) = match result {
+            Some(Ok(())) => {
+                self.record(&Ok(()));
+                self.shared
+                    .delivered
+                    .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                return true;
+            }
+            Some(Err((sent, e))) => {
+                self.record(&Err(&e));
+                self.shared
+                    .delivered
+                    .fetch_add(sent as u64, Ordering::Relaxed);
+                (e.is_transient(), Some(e), &batch[sent..])
+            }
+            None => (true, None, &batch[..]),
+        };
+        let count = batch.len() as u64;
+        let reason = error
+            .as_ref()
+            .map(|e| e.to_string())
+            .unwrap_or_else(|| "circuit is open".to_string());
+
+        let spooled = match self.spool.as_mut() {
+            Some(spool) if unreachable => Some(spool.append(batch)),
+            _ => None,
+        };
+
+        match spooled {
+            Some(Ok(appended)) => {
+                let rejected = appended.rejected;
+                self.shared
+                    .spooled
+                    .fetch_add(count - rejected, Ordering::Relaxed);
+                self.shared
+                    .failed
+                    .fetch_add(appended.lost + rejected, Ordering::Relaxed);
+                match rejected {
+                    0 => log::debug!(
+                        "Treblle payload delivery failed, payload spooled: {}",
+                        reason
+                    ),
+                    _ => log::warn!(
+                        "Treblle payload delivery failed and {} payloads didn't fit into the spool: {}",
+                        rejected,
+                        reason
+                    ),
+                }
+            }
+            Some(Err(e)) => {
+                self.shared.failed.fetch_add(count, Ordering::Relaxed);
+                log::warn!("Treblle payload couldn't be spooled: {}", e);
+                report(self.on_error.as_ref(), TreblleError::Spool(e));
+            }
+            None => {
+                self.shared.failed.fetch_add(count, Ordering::Relaxed);
+                log::debug!("Treblle payload delivery failed: {}", reason);
+                if let Some(e) = error {
+                    report(self.on_error.as_ref(), e.into());
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Ask the circuit breaker if the endpoint should be tried
+    fn allow(&self) -> bool {
+        self.breaker
+            .as_ref()
+            .map(|breaker| breaker.allow())
+            .unwrap_or(true)
+    }
+
+    /// Let the circuit breaker know how the delivery went, only failures that mean the
+    /// endpoint is unreachable count against it
+    fn record(&self, result: &Result<(), &TransportError>) {
+        if let Some(breaker) = &self.breaker {
+            match result {
+                Err(e) if e.is_transient() => breaker.record_failure(),
+                _ => breaker.record_success(),
+            }
+        }
+    }
+
+    /// Send everything from the spool, oldest segment first, until the spool is empty or
+    /// the endpoint fails again
+    async fn drain_spool(&mut self) {
+        let chunk = self
+            .config
+            .batching
+            .map(|batching| batching.max_payloads.max(1))
+            .unwrap_or(1);
+
+        loop {
+            let oldest = match self.spool.as_mut().map(|spool| spool.oldest()) {
+                Some(Ok(Some(oldest))) => oldest,
+                Some(Err(e)) => {
+                    log::warn!("Treblle spool couldn't be read: {}", e);
+                    report(self.on_error.as_ref(), TreblleError::Spool(e));
+                    return;
+                }
+                _ => return,
+            };
+            let (seq, payloads) = oldest;
+
+            let mut sent = 0;
+            for batch in payloads.chunks(chunk) {
+                if !self.allow() {
+                    break;
+                }
+
+                let result = self.send(batch).await;
+                self.record(&result.as_ref().map(|_| ()).map_err(|(_, e)| e));
+                if let Err((delivered, e)) = result {
+                    log::debug!("Treblle spool delivery failed: {}", e);
+                    sent += delivered;
+                    break;
+                }
+                sent += batch.len();
+            }
+            self.shared
+                .delivered
+                .fetch_add(sent as u64, Ordering::Relaxed);
+
+            let spool = match self.spool.as_mut() {
+                Some(spool) => spool,
+                None => return,
+            };
+            if sent == payloads.len() {
+                if let Err(e) = spool.remove(seq) {
+                    log::warn!("Treblle spool segment couldn't be removed: {}", e);
+                    report(self.on_error.as_ref(), TreblleError::Spool(e));
+                    return;
+                }
+            } else {
+                if let Err(e) = spool.rewrite(seq, &payloads[sent..]) {
+                    log::warn!("Treblle spool segment couldn't be rewritten: {}", e);
+                    report(self.on_error.as_ref(), TreblleError::Spool(e));
+                }
+                return;
+            }
+        }
+    }
+
+    /// Send the batch, retrying transient failures according to the retry policy. Payloads
+    /// that were delivered before a failure aren't sent again, the error comes with their
+    /// number.
+    async fn send_with_retry(&self, batch: &[TreblleData]) -> Result<(), (usize, TransportError)> {
+        let mut sent = 0;
+        let mut attempt = 1;
+        loop {
+            let e = match self.send(&batch[sent..]).await {
+                Ok(()) => return Ok(()),
+                Err((delivered, e)) => {
+                    sent += delivered;
+                    e
+                }
+            };
+
+            let retry = match self.config.retry {
+                Some(retry) if e.is_transient() && attempt < retry.max_attempts => retry,
+                _ => return Err((sent, e)),
+            };
+
+            let backoff = match retry.backoff(attempt, &e) {
+                Some(backoff) => backoff,
+                None => {
+                    log::debug!("Treblle endpoint asked to retry later than the backoff allows");
+                    return Err((sent, e));
+                }
+            };
+            log::debug!(
+                "Treblle payload delivery failed, retrying in {:?}: {}",
+                backoff,
+                e
+            );
+
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Number of bytes the payload takes once serialized, without allocating it
+fn serialized_size(data: &TreblleData) -> usize {
+    let mut counter = ByteCounter(0);
+    let _ = serde_json::to_writer(&mut counter, data);
+
+    counter.0
+}
+
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Batching, DeliveryConfig, Dispatcher, DropPolicy, Push, Queue, Retry};
+    use crate::{Transport, TransportError, TreblleData};
+    use futures::future::BoxFuture;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct BatchRecorder {
+        batches: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Transport for BatchRecorder {
+        fn send<'a>(
+            &'a self,
+            payload: &'a TreblleData,
+        ) -> BoxFuture<'a, Result<(), TransportError>> {
+            Box::pin(async move {
+                self.send_batch(std::slice::from_ref(payload))
+                    .await
+                    .map_err(|(_, e)| e)
+            })
+        }
+
+        fn send_batch<'a>(
+            &'a self,
+            payloads: &'a [TreblleData],
+        ) -> BoxFuture<'a, Result<(), (usize, TransportError)>> {
+            self.batches.lock().unwrap().push(payloads.len());
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn drop_newest_keeps_queued_items() {
+        let queue = Queue::new(2, DropPolicy::DropNewest);
+        assert_eq!(queue.push(1).await, Push::Accepted);
+        assert_eq!(queue.push(2).await, Push::Accepted);
+        assert_eq!(queue.push(3).await, Push::Rejected(3));
+
+        assert_eq!(queue.pop().await, Some(1));
+        assert_eq!(queue.pop().await, Some(2));
+    }
+
+    #[actix_rt::test]
+    async fn drop_oldest_makes_room() {
+        let queue = Queue::new(2, DropPolicy::DropOldest);
+        assert_eq!(queue.push(1).await, Push::Accepted);
+        assert_eq!(queue.push(2).await, Push::Accepted);
+        assert_eq!(queue.push(3).await, Push::Replaced(1));
+
+        assert_eq!(queue.pop().await, Some(2));
+        assert_eq!(queue.pop().await, Some(3));
+    }
+
+    #[actix_rt::test]
+    async fn block_waits_for_room() {
+        let queue = std::sync::Arc::new(Queue::new(1, DropPolicy::Block));
+        assert_eq!(queue.push(1).await, Push::Accepted);
+
+        let producer = queue.clone();
+        let pushed = actix_rt::spawn(async move { producer.push(2).await });
+
+        assert_eq!(queue.pop().await, Some(1));
+        assert_eq!(pushed.await.unwrap(), Push::Accepted);
+        assert_eq!(queue.pop().await, Some(2));
+
+        queue.close();
+        assert_eq!(queue.pop().await, None);
+    }
+
+    #[actix_rt::test]
+    async fn batches_payloads_until_a_limit_is_reached() {
+        let recorder = BatchRecorder::default();
+        let dispatcher = Dispatcher::start(
+            Arc::new(recorder.clone()),
+            DeliveryConfig {
+                batching: Some(Batching {
+                    max_payloads: 2,
+                    max_bytes: usize::MAX,
+                    linger: Duration::from_millis(50),
+                }),
+                ..DeliveryConfig::default()
+            },
+            None,
+        );
+
+        for _ in 0..3 {
+            dispatcher
+                .enqueue(TreblleData::new(
+                    "api_key".to_string(),
+                    "project_id".to_string(),
+                ))
+                .await;
+        }
+
+        assert!(dispatcher.flush(Duration::from_secs(1)).await);
+
+        assert_eq!(dispatcher.stats().delivered, 3);
+        assert_eq!(*recorder.batches.lock().unwrap(), vec![2, 1]);
+    }
+
+    #[derive(Clone, Default)]
+    struct Flaky {
+        attempts: Arc<AtomicU32>,
+    }
+
+    impl Transport for Flaky {
+        fn send<'a>(
+            &'a self,
+            _payload: &'a TreblleData,
+        ) -> BoxFuture<'a, Result<(), TransportError>> {
+            Box::pin(async move {
+                if self.attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    return Err(TransportError::Status {
+                        code: 503,
+                        body: String::new(),
+                        retry_after: None,
+                    });
+                }
+
+                Ok(())
+            })
+        }
+    }
+
+    /// Fails the second payload it's given once, with the given status code
+    #[derive(Clone)]
+    struct FailsSecond {
+        code: u16,
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FailsSecond {
+        fn new(code: u16) -> FailsSecond {
+            FailsSecond {
+                code,
+                sent: Arc::default(),
+            }
+        }
+    }
+
+    impl Transport for FailsSecond {
+        fn send<'a>(
+            &'a self,
+            payload: &'a TreblleData,
+        ) -> BoxFuture<'a, Result<(), TransportError>> {
+            Box::pin(async move {
+                let mut sent = self.sent.lock().unwrap();
+                sent.push(payload.project_id.clone());
+                if sent.len() == 2 {
+                    return Err(TransportError::Status {
+                        code: self.code,
+                        body: String::new(),
+                        retry_after: None,
+                    });
+                }
+
+                Ok(())
+            })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn resends_only_the_rest_of_a_failed_batch() {
+        let batching = Batching {
+            max_payloads: 3,
+            max_bytes: usize::MAX,
+            linger: Duration::from_secs(1),
+        };
+        let payloads = || {
+            ["first", "second", "third"]
+                .map(|project_id| TreblleData::new("api_key".to_string(), project_id.to_string()))
+        };
+
+        let retried = FailsSecond::new(503);
+        let dispatcher = Dispatcher::start(
+            Arc::new(retried.clone()),
+            DeliveryConfig {
+                batching: Some(batching),
+                retry: Some(Retry {
+                    max_attempts: 2,
+                    initial_backoff: Duration::from_millis(1),
+                    max_backoff: Duration::from_millis(5),
+                }),
+                ..DeliveryConfig::default()
+            },
+            None,
+        );
+        for payload in payloads() {
+            dispatcher.enqueue(payload).await;
+        }
+        assert!(dispatcher.flush(Duration::from_secs(1)).await);
+
+        assert_eq!(
+            *retried.sent.lock().unwrap(),
+            vec!["first", "second", "second", "third"]
+        );
+        assert_eq!(dispatcher.stats().delivered, 3);
+        assert_eq!(dispatcher.stats().failed, 0);
+
+        let failed = FailsSecond::new(400);
+        let dispatcher = Dispatcher::start(
+            Arc::new(failed.clone()),
+            DeliveryConfig {
+                batching: Some(batching),
+                ..DeliveryConfig::default()
+            },
+            None,
+        );
+        for payload in payloads() {
+            dispatcher.enqueue(payload).await;
+        }
+        assert!(dispatcher.flush(Duration::from_secs(1)).await);
+
+        assert_eq!(*failed.sent.lock().unwrap(), vec!["first", "second"]);
+        assert_eq!(dispatcher.stats().delivered, 1);
+        assert_eq!(dispatcher.stats().failed, 2);
+    }
+
+    struct Panicking {
+        attempts: AtomicU32,
+    }
+
+    impl Transport for Panicking {
+        fn send<'a>(
+            &'a self,
+            _payload: &'a TreblleData,
+        ) -> BoxFuture<'a, Result<(), TransportError>> {
+            Box::pin(async move {
+                if self.attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("first payload");
+                }
+
+                Ok(())
+            })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn keeps_delivering_after_the_transport_panicked() {
+        let errors = Arc::new(Mutex::new(vec![]));
+        let reported = errors.clone();
+        let dispatcher = Dispatcher::start(
+            Arc::new(Panicking {
+                attempts: AtomicU32::new(0),
+            }),
+            DeliveryConfig::default(),
+            Some(Arc::new(move |e| {
+                reported.lock().unwrap().push(e.to_string())
+            })),
+        );
+
+        for _ in 0..2 {
+            dispatcher
+                .enqueue(TreblleData::new(
+                    "api_key".to_string(),
+                    "project_id".to_string(),
+                ))
+                .await;
+        }
+        assert!(dispatcher.flush(Duration::from_secs(1)).await);
+
+        assert_eq!(dispatcher.stats().failed, 1);
+        assert_eq!(dispatcher.stats().delivered, 1);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec!["Treblle payload delivery failed: transport panicked: first payload"]
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct Slow {
+        delivered: Arc<AtomicU32>,
+    }
+
+    impl Transport for Slow {
+        fn send<'a>(
+            &'a self,
+            _payload: &'a TreblleData,
+        ) -> BoxFuture<'a, Result<(), TransportError>> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                self.delivered.fetch_add(1, Ordering::SeqCst);
+
+                Ok(())
+            })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn flush_waits_for_queued_and_in_flight_payloads() {
+        let slow = Slow::default();
+        let dispatcher = Dispatcher::start(Arc::new(slow.clone()), DeliveryConfig::default(), None);
+        let payload = || TreblleData::new("api_key".to_string(), "project_id".to_string());
+
+        for _ in 0..3 {
+            dispatcher.enqueue(payload()).await;
+        }
+        assert!(!dispatcher.flush(Duration::from_millis(10)).await);
+        assert!(dispatcher.flush(Duration::from_secs(1)).await);
+        assert_eq!(slow.delivered.load(Ordering::SeqCst), 3);
+
+        dispatcher.enqueue(payload()).await;
+        assert!(dispatcher.shutdown(Duration::from_secs(1)).await);
+        assert_eq!(slow.delivered.load(Ordering::SeqCst), 4);
+
+        dispatcher.enqueue(payload()).await;
+        assert!(dispatcher.flush(Duration::from_secs(1)).await);
+        assert_eq!(slow.delivered.load(Ordering::SeqCst), 4);
+        assert_eq!(dispatcher.stats().dropped, 1);
+    }
+
+    #[actix_rt::test]
+    async fn close_wakes_up_blocked_producers() {
+        let queue = Arc::new(Queue::new(1, DropPolicy::Block));
+        assert_eq!(queue.push(1).await, Push::Accepted);
+
+        let producers = (2..5)
+            .map(|i| {
+                let queue = queue.clone();
+                actix_rt::spawn(async move { queue.push(i).await })
+            })
+            .collect::<Vec<_>>();
+        actix_rt::task::yield_now().await;
+        queue.close();
+
+        for (i, producer) in (2..5).zip(producers) {
+            assert_eq!(producer.await.unwrap(), Push::Rejected(i));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn retries_transient_failures() {
+        let flaky = Flaky::default();
+        let dispatcher = Dispatcher::start(
+            Arc::new(flaky.clone()),
+            DeliveryConfig {
+                retry: Some(Retry {
+                    max_attempts: 3,
+                    initial_backoff: Duration::from_millis(1),
+                    max_backoff: Duration::from_millis(5),
+                }),
+                ..DeliveryConfig::default()
+            },
+            None,
+        );
+
+        dispatcher
+            .enqueue(TreblleData::new(
+                "api_key".to_string(),
+                "project_id".to_string(),
+            ))
+            .await;
+        assert!(dispatcher.flush(Duration::from_secs(1)).await);
+
+        assert_eq!(dispatcher.stats().delivered, 1);
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[derive(Clone, Default)]
+    struct Throttled {
+        attempts: Arc<AtomicU32>,
+    }
+
+    impl Transport for Throttled {
+        fn send<'a>(
+            &'a self,
+            _payload: &'a TreblleData,
+        ) -> BoxFuture<'a, Result<(), TransportError>> {
+            Box::pin(async move {
+                self.attempts.fetch_add(1, Ordering::SeqCst);
+
+                Err(TransportError::Status {
+                    code: 429,
+                    body: String::new(),
+                    retry_after: Some(Duration::from_secs(60)),
+                })
+            })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn does_not_retry_sooner_than_retry_after_allows() {
+        let retry = Retry {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_secs(10),
+        };
+        let throttled = |seconds| TransportError::Status {
+            code: 429,
+            body: String::new(),
+            retry_after: Some(Duration::from_secs(seconds)),
+        };
+        assert_eq!(
+            retry.backoff(1, &throttled(5)),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(retry.backoff(1, &throttled(60)), None);
+
+        let transport = Throttled::default();
+        let dispatcher = Dispatcher::start(
+            Arc::new(transport.clone()),
+            DeliveryConfig {
+                retry: Some(retry),
+                ..DeliveryConfig::default()
+            },
+            None,
+        );
+        dispatcher
+            .enqueue(TreblleData::new(
+                "api_key".to_string(),
+                "project_id".to_string(),
+            ))
+            .await;
+        assert!(dispatcher.flush(Duration::from_secs(1)).await);
+
+        assert_eq!(transport.attempts.load(Ordering::SeqCst), 1);
+        assert_eq!(dispatcher.stats().failed, 1);
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::transport::TransportError;
+
+/// Callback that receives the failures of the SDK
+pub(crate) type ErrorHandler = Arc<dyn Fn(&TreblleError) + Send + Sync>;
+
+/// Failure of the SDK, it never affects the requests of your application
+#[derive(Debug)]
+pub enum TreblleError {
+    /// Request body couldn't be read while it was being captured
+    Capture(String),
+    /// Payload couldn't be serialized
+    Serialization(serde_json::Error),
+    /// Body that isn't JSON has some of the masking fields as keys, since they can't be
+    /// masked one by one the whole body was masked
+    Masking { fields: Vec<String> },
+    /// Payload couldn't be delivered
+    Delivery(TransportError),
+    /// Spool couldn't be read or written
+    Spool(std::io::Error),
+}
+
+impl fmt::Display for TreblleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreblleError::Capture(e) => write!(f, "Treblle couldn't capture the request: {}", e),
+            TreblleError::Serialization(e) => {
+                write!(f, "Treblle payload serialization failed: {}", e)
+            }
+            TreblleError::Masking { fields } => write!(
+                f,
+                "Treblle masked the whole body because it mentions: {}",
+                fields.join(", ")
+            ),
+            TreblleError::Delivery(e) => write!(f, "Treblle payload delivery failed: {}", e),
+            TreblleError::Spool(e) => write!(f, "Treblle spool failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TreblleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TreblleError::Serialization(e) => Some(e),
+            TreblleError::Delivery(e) => Some(e),
+            TreblleError::Spool(e) => Some(e),
+            TreblleError::Capture(_) | TreblleError::Masking { .. } => None,
+        }
+    }
+}
+
+impl From<TransportError> for TreblleError {
+    fn from(e: TransportError) -> TreblleError {
+        match e {
+            TransportError::Serialization(e) => TreblleError::Serialization(e),
+            e => TreblleError::Delivery(e),
+        }
+    }
+}
+
+/// Pass the failure to the user's callback, if there is one
+pub(crate) fn report(handler: Option<&ErrorHandler>, e: TreblleError) {
+    if let Some(handler) = handler {
+        handler(&e);
+    }
+}
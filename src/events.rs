@@ -0,0 +1,288 @@
+use futures::future::BoxFuture;
+
+use crate::payload::TreblleData;
+use crate::transport::{Transport, TransportError};
+
+/// Transport that emits every payload as a single `log` record with key/value pairs,
+/// so it fits into structured log pipelines: `method`, `url`, `status`, `load_time`,
+/// `ip`, `sample_rate` and `errors`, plus `request_body` and `response_body` as JSON
+/// strings when bodies are turned on.
+///
+/// The key/value pairs are only written out by loggers that support them, for example
+/// `env_logger` with its `kv` feature or `structured-logger`.
+///
+/// ```rust,ignore
+/// let treblle = actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+///     .sink(actix_treblle::Sink::new(
+///         "log",
+///         actix_treblle::LogTransport::new().level(log::Level::Debug).bodies(),
+///     ));
+/// ```
+#[derive(Debug, Clone)]
+pub struct LogTransport {
+    target: String,
+    level: log::Level,
+    bodies: bool,
+}
+
+impl Default for LogTransport {
+    fn default() -> LogTransport {
+        LogTransport {
+            target: "actix_treblle".to_string(),
+            level: log::Level::Info,
+            bodies: false,
+        }
+    }
+}
+
+impl LogTransport {
+    /// Emit records on the `actix_treblle` target with the info level
+    pub fn new() -> LogTransport {
+        LogTransport::default()
+    }
+
+    /// Target of the records
+    pub fn target<T: Into<String>>(mut self, target: T) -> LogTransport {
+        self.target = target.into();
+        self
+    }
+
+    /// Level of the records
+    pub fn level(mut self, level: log::Level) -> LogTransport {
+        self.level = level;
+        self
+    }
+
+    /// Add the request and response bodies to the records
+    pub fn bodies(mut self) -> LogTransport {
+        self.bodies = true;
+        self
+    }
+}
+
+impl Transport for LogTransport {
+    fn send<'a>(&'a self, payload: &'a TreblleData) -> BoxFuture<'a, Result<(), TransportError>> {
+        Box::pin(async move {
+            let fields = Fields::new(payload, self.bodies)?;
+            log::log!(
+                target: &self.target,
+                self.level,
+                method = fields.method,
+                url = fields.url,
+                status = fields.status,
+                load_time = fields.load_time,
+                ip = fields.ip,
+                sample_rate = fields.sample_rate,
+                errors = fields.errors.as_deref(),
+                request_body = fields.request_body.as_deref(),
+                response_body = fields.response_body.as_deref();
+                "{} {} {}",
+                fields.method,
+                fields.url,
+                fields.status.unwrap_or_default()
+            );
+
+            Ok(())
+        })
+    }
+}
+
+/// Transport that emits every payload as a `tracing` event on the `actix_treblle`
+/// target, with the same fields as [`LogTransport`].
+///
+/// ```rust,ignore
+/// let treblle = actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+///     .sink(actix_treblle::Sink::new(
+///         "tracing",
+///         actix_treblle::TracingTransport::new().level(tracing::Level::DEBUG),
+///     ));
+/// ```
+#[cfg(feature = "tracing")]
+#[derive(Debug, Clone)]
+pub struct TracingTransport {
+    level: tracing::Level,
+    bodies: bool,
+}
+
+#[cfg(feature = "tracing")]
+impl Default for TracingTransport {
+    fn default() -> TracingTransport {
+        TracingTransport {
+            level: tracing::Level::INFO,
+            bodies: false,
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl TracingTransport {
+    /// Emit events with the info level
+    pub fn new() -> TracingTransport {
+        TracingTransport::default()
+    }
+
+    /// Level of the events
+    pub fn level(mut self, level: tracing::Level) -> TracingTransport {
+        self.level = level;
+        self
+    }
+
+    /// Add the request and response bodies to the events
+    pub fn bodies(mut self) -> TracingTransport {
+        self.bodies = true;
+        self
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Transport for TracingTransport {
+    fn send<'a>(&'a self, payload: &'a TreblleData) -> BoxFuture<'a, Result<(), TransportError>> {
+        // Level of the tracing callsites has to be known at compile time
+        macro_rules! event {
+            ($level:expr, $fields:expr) => {
+                tracing::event!(
+                    target: "actix_treblle",
+                    $level,
+                    method = $fields.method,
+                    url = $fields.url,
+                    status = $fields.status,
+                    load_time = $fields.load_time,
+                    ip = $fields.ip,
+                    sample_rate = $fields.sample_rate,
+                    errors = $fields.errors.as_deref(),
+                    request_body = $fields.request_body.as_deref(),
+                    response_body = $fields.response_body.as_deref(),
+                    "{} {} {}",
+                    $fields.method,
+                    $fields.url,
+                    $fields.status.unwrap_or_default()
+                )
+            };
+        }
+
+        Box::pin(async move {
+            let fields = Fields::new(payload, self.bodies)?;
+            match self.level {
+                tracing::Level::ERROR => event!(tracing::Level::ERROR, fields),
+                tracing::Level::WARN => event!(tracing::Level::WARN, fields),
+                tracing::Level::INFO => event!(tracing::Level::INFO, fields),
+                tracing::Level::DEBUG => event!(tracing::Level::DEBUG, fields),
+                tracing::Level::TRACE => event!(tracing::Level::TRACE, fields),
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Values of the payload that are emitted as fields
+struct Fields<'a> {
+    method: &'a str,
+    url: &'a str,
+    status: Option<u16>,
+    load_time: Option<f64>,
+    ip: Option<&'a str>,
+    sample_rate: Option<f64>,
+    errors: Option<String>,
+    request_body: Option<String>,
+    response_body: Option<String>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(payload: &'a TreblleData, bodies: bool) -> Result<Fields<'a>, serde_json::Error> {
+        let request = &payload.data.request;
+        let response = &payload.data.response;
+
+        let errors = match payload.data.errors.is_empty() {
+            true => None,
+            false => Some(serde_json::to_string(&payload.data.errors)?),
+        };
+        let body = |body: &Option<serde_json::Value>| match body {
+            Some(body) if bodies && !body.is_null() => serde_json::to_string(body).map(Some),
+            _ => Ok(None),
+        };
+
+        Ok(Fields {
+            method: request.method.as_deref().unwrap_or_default(),
+            url: request.url.as_deref().unwrap_or_default(),
+            status: response.code,
+            load_time: response
+                .load_time
+                .as_deref()
+                .and_then(|load_time| load_time.parse().ok()),
+            ip: request.ip.as_deref(),
+            sample_rate: payload.sample_rate,
+            errors,
+            request_body: body(&request.body)?,
+            response_body: body(&response.body)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LogTransport;
+    use crate::payload::TreblleData;
+    use crate::transport::Transport;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    struct Capture {
+        records: Mutex<Vec<HashMap<String, String>>>,
+    }
+
+    impl log::Log for Capture {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            struct Collect(HashMap<String, String>);
+
+            impl<'kvs> log::kv::VisitSource<'kvs> for Collect {
+                fn visit_pair(
+                    &mut self,
+                    key: log::kv::Key<'kvs>,
+                    value: log::kv::Value<'kvs>,
+                ) -> Result<(), log::kv::Error> {
+                    self.0.insert(key.to_string(), value.to_string());
+                    Ok(())
+                }
+            }
+
+            if record.target() == "events_test" {
+                let mut collect = Collect(HashMap::new());
+                record.key_values().visit(&mut collect).unwrap();
+                self.records.lock().unwrap().push(collect.0);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture {
+        records: Mutex::new(vec![]),
+    };
+
+    #[actix_rt::test]
+    async fn emits_payload_as_key_values() {
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let mut payload = TreblleData::new("api_key".to_string(), "project_id".to_string());
+        payload.data.request.method = Some("POST".to_string());
+        payload.data.request.url = Some("http://localhost/hello".to_string());
+        payload.data.request.body = Some(serde_json::json!({"name": "treblle"}));
+        payload.data.response.code = Some(201);
+
+        let transport = LogTransport::new().target("events_test").bodies();
+        transport.send(&payload).await.unwrap();
+
+        let records = CAPTURE.records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["method"], "POST");
+        assert_eq!(records[0]["url"], "http://localhost/hello");
+        assert_eq!(records[0]["status"], "201");
+        assert_eq!(records[0]["request_body"], r#"{"name":"treblle"}"#);
+    }
+}
@@ -2,6 +2,7 @@ use actix_web::{
     body::{BodySize, BoxBody, MessageBody},
     dev::ServiceResponse,
     http::header::map::HeaderMap,
+    web::Bytes,
 };
 use chrono::Utc;
 use serde_json::{Map, Value};
@@ -34,7 +35,8 @@ impl Extractor {
         self.sr.response().status().as_u16()
     }
 
-    /// Get the size of the body in the response
+    /// Get the size of the body in the response, the size of bodies that stream is
+    /// counted while they are sent
     pub fn get_size(&self) -> u64 {
         match self.sr.response().body().size() {
             BodySize::Sized(v) => v,
@@ -119,9 +121,9 @@ impl Extractor {
         self.sr.request().method().to_string()
     }
 
-    /// Clone the response body and extract it into Value if its possible,
-    /// if not, we'll treat it as Null.
-    pub fn get_response_body(self) -> (ServiceResponse, Value) {
+    /// Get the response body when it's already in memory, bodies that stream return
+    /// `None` since they can only be captured while they are sent
+    pub fn get_response_body(self) -> (ServiceResponse, Option<Value>) {
         let mut bytes = None;
         let sr = self
             .sr
@@ -134,25 +136,22 @@ impl Extractor {
                 Err(same_old_body) => same_old_body,
             });
 
-        (
-            sr,
-            match bytes {
-                Some(b) => {
-                    if b.is_empty() {
-                        Value::Null
-                    } else {
-                        match serde_json::from_slice::<Value>(&b) {
-                            Ok(v) => v,
-                            Err(_) => match String::from_utf8(b.to_vec()) {
-                                Ok(s) => Value::String(s),
-                                Err(_) => Value::String(format!("{:?}", b)),
-                            },
-                        }
-                    }
-                }
-                None => Value::Null,
+        (sr, bytes.map(|b| parse_response_body(&b)))
+    }
+}
+
+/// Parse the response body as JSON, bodies that aren't JSON are kept as strings
+pub(crate) fn parse_response_body(b: &[u8]) -> Value {
+    if b.is_empty() {
+        Value::Null
+    } else {
+        match serde_json::from_slice::<Value>(b) {
+            Ok(v) => v,
+            Err(_) => match String::from_utf8(b.to_vec()) {
+                Ok(s) => Value::String(s),
+                Err(_) => Value::String(format!("{:?}", Bytes::copy_from_slice(b))),
             },
-        )
+        }
     }
 }
 
@@ -0,0 +1,80 @@
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::breaker::CircuitState;
+use crate::delivery::{DeliveryStats, Dispatcher};
+
+/// Handle to the background delivery of payloads, get it with [`crate::Treblle::handle`]
+/// before giving the middleware to the `HttpServer`.
+///
+/// ```rust,ignore
+/// let treblle = actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string());
+/// let handle = treblle.handle();
+///
+/// HttpServer::new(move || {
+///     App::new()
+///         .wrap(treblle.clone())
+///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+/// })
+/// .bind(("127.0.0.1", 8080))?
+/// .run()
+/// .await?;
+///
+/// handle.shutdown(std::time::Duration::from_secs(5)).await;
+/// ```
+#[derive(Clone)]
+pub struct TreblleHandle {
+    pub(crate) dispatcher: Arc<OnceLock<Arc<Dispatcher>>>,
+    pub(crate) sinks: Vec<Arc<OnceLock<Arc<Dispatcher>>>>,
+}
+
+impl TreblleHandle {
+    /// Wait until every queued and in-flight payload is delivered, spooled or given up on,
+    /// for Treblle.com and all the sinks. Returns `false` if some payloads are still
+    /// pending after the timeout.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        let flushes = self
+            .dispatchers()
+            .map(|dispatcher| async move { dispatcher.flush(timeout).await });
+        futures::future::join_all(flushes)
+            .await
+            .into_iter()
+            .all(|done| done)
+    }
+
+    /// Stop accepting new payloads and wait for the pending ones like [`TreblleHandle::flush`].
+    /// Requests that come in after the shutdown won't be logged.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        let shutdowns = self
+            .dispatchers()
+            .map(|dispatcher| async move { dispatcher.shutdown(timeout).await });
+        futures::future::join_all(shutdowns)
+            .await
+            .into_iter()
+            .all(|done| done)
+    }
+
+    /// Counters of queued, dropped, delivered and failed payloads of Treblle.com, use
+    /// [`crate::Sink::delivery_stats`] for the sinks
+    pub fn stats(&self) -> DeliveryStats {
+        self.dispatcher
+            .get()
+            .map(|dispatcher| dispatcher.stats())
+            .unwrap_or_default()
+    }
+
+    /// Current state of the circuit breaker, it's always closed when the circuit breaker
+    /// isn't turned on
+    pub fn circuit_state(&self) -> CircuitState {
+        self.dispatcher
+            .get()
+            .map(|dispatcher| dispatcher.circuit_state())
+            .unwrap_or(CircuitState::Closed)
+    }
+
+    fn dispatchers(&self) -> impl Iterator<Item = &Arc<Dispatcher>> {
+        std::iter::once(&self.dispatcher)
+            .chain(self.sinks.iter())
+            .filter_map(|dispatcher| dispatcher.get())
+    }
+}
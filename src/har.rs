@@ -0,0 +1,282 @@
+use actix_web::http::StatusCode;
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::payload::TreblleData;
+use crate::transport::{Transport, TransportError};
+
+/// Convert the payload into an entry of a HAR 1.2 log, so the captured traffic can be
+/// opened in the browser devtools and other HTTP tools.
+///
+/// Values that aren't captured, like cookies, the HTTP version or the sizes of headers,
+/// are left empty or set to `-1` as the HAR specification asks for. Load time is
+/// reported as the time waiting for the response. Client IP is added as the custom
+/// `_clientIPAddress` field.
+pub fn har_entry(data: &TreblleData) -> Value {
+    let request = &data.data.request;
+    let response = &data.data.response;
+
+    let url = request.url.clone().unwrap_or_default();
+    let query = reqwest::Url::parse(&url)
+        .map(|url| {
+            url.query_pairs()
+                .map(|(name, value)| json!({ "name": name, "value": value }))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    // HTTP version of the request isn't captured, the protocol only has the scheme
+    let http_version = "";
+    let time = response
+        .load_time
+        .as_deref()
+        .and_then(|load_time| load_time.parse::<f64>().ok())
+        .map(|seconds| seconds * 1000.0)
+        .unwrap_or(0.0);
+    let status = response.code.unwrap_or_default();
+
+    let mut har_request = json!({
+        "method": request.method.clone().unwrap_or_default(),
+        "url": url,
+        "httpVersion": http_version,
+        "cookies": [],
+        "headers": har_headers(&request.headers),
+        "queryString": query,
+        "headersSize": -1,
+        "bodySize": -1,
+    });
+    if let Some(text) = body_text(request.body.as_ref()) {
+        har_request["postData"] = json!({
+            "mimeType": header(&request.headers, "content-type"),
+            "text": text,
+        });
+    }
+
+    let content_text = body_text(response.body.as_ref());
+    let mut content = json!({
+        "size": response.size.unwrap_or_default(),
+        "mimeType": header(&response.headers, "content-type"),
+    });
+    if let Some(text) = content_text {
+        content["text"] = Value::String(text);
+    }
+
+    json!({
+        "startedDateTime": data.start.to_rfc3339(),
+        "time": time,
+        "request": har_request,
+        "response": {
+            "status": status,
+            "statusText": StatusCode::from_u16(status)
+                .ok()
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or_default(),
+            "httpVersion": http_version,
+            "cookies": [],
+            "headers": har_headers(&response.headers),
+            "content": content,
+            "redirectURL": header(&response.headers, "location"),
+            "headersSize": -1,
+            "bodySize": response.size.map(|size| size as i64).unwrap_or(-1),
+        },
+        "cache": {},
+        "timings": {
+            "send": 0,
+            "wait": time,
+            "receive": 0,
+        },
+        "_clientIPAddress": request.ip,
+    })
+}
+
+/// Transport that keeps the latest payloads as a HAR 1.2 file which is rewritten on
+/// every delivery, turn on batching of the sink to rewrite it less often.
+///
+/// ```rust,ignore
+/// let treblle = actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+///     .sink(actix_treblle::Sink::new(
+///         "har",
+///         actix_treblle::HarFile::new("/tmp/treblle.har").max_entries(500),
+///     ));
+/// ```
+pub struct HarFile {
+    path: PathBuf,
+    max_entries: usize,
+    /// Entries of the file, loaded from the existing file on the first write
+    entries: Mutex<Option<VecDeque<Value>>>,
+}
+
+impl HarFile {
+    /// Write the HAR log to the given path, by default the latest 1000 entries are kept.
+    /// Entries of an existing file are kept and the new ones are added after them.
+    pub fn new<P: Into<PathBuf>>(path: P) -> HarFile {
+        HarFile {
+            path: path.into(),
+            max_entries: 1000,
+            entries: Mutex::new(None),
+        }
+    }
+
+    /// Number of the latest entries kept in the file
+    pub fn max_entries(mut self, max_entries: usize) -> HarFile {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Entries of the file written before, a file that can't be read as a HAR log is
+    /// replaced
+    fn load(&self) -> VecDeque<Value> {
+        let har = match fs::read(&self.path) {
+            Ok(har) => har,
+            Err(_) => return VecDeque::new(),
+        };
+
+        match serde_json::from_slice::<Value>(&har) {
+            Ok(mut har) => match har["log"]["entries"].take() {
+                Value::Array(entries) => entries.into(),
+                _ => VecDeque::new(),
+            },
+            Err(e) => {
+                log::warn!(
+                    "Treblle HAR file {} couldn't be read and will be replaced: {}",
+                    self.path.display(),
+                    e
+                );
+                VecDeque::new()
+            }
+        }
+    }
+
+    fn write(&self, payloads: &[TreblleData]) -> Result<(), TransportError> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = entries.get_or_insert_with(|| self.load());
+        entries.extend(payloads.iter().map(har_entry));
+        while entries.len() > self.max_entries {
+            entries.pop_front();
+        }
+
+        let har = json!({
+            "log": {
+                "version": "1.2",
+                "creator": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "entries": *entries,
+            }
+        });
+
+        // Replace the file at once so it can be opened at any time
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, serde_json::to_vec(&har)?)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl Transport for HarFile {
+    fn send<'a>(&'a self, payload: &'a TreblleData) -> BoxFuture<'a, Result<(), TransportError>> {
+        Box::pin(async move { self.write(std::slice::from_ref(payload)) })
+    }
+
+    fn send_batch<'a>(
+        &'a self,
+        payloads: &'a [TreblleData],
+    ) -> BoxFuture<'a, Result<(), (usize, TransportError)>> {
+        Box::pin(async move { self.write(payloads).map_err(|e| (0, e)) })
+    }
+}
+
+fn har_headers(headers: &HashMap<String, String>) -> Vec<Value> {
+    let mut headers = headers
+        .iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect::<Vec<_>>();
+    headers.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+
+    headers
+}
+
+fn header(headers: &HashMap<String, String>, name: &str) -> String {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.clone())
+        .unwrap_or_default()
+}
+
+/// Body as it was sent, bodies that weren't JSON are stored as strings
+fn body_text(body: Option<&Value>) -> Option<String> {
+    match body? {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        Value::Object(map) if map.len() == 1 && map.contains_key("request_as_a_string") => {
+            map["request_as_a_string"].as_str().map(|s| s.to_string())
+        }
+        body => Some(body.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{har_entry, HarFile};
+    use crate::payload::TreblleData;
+    use crate::transport::Transport;
+
+    #[test]
+    fn converts_payload_into_entry() {
+        let mut payload = TreblleData::new("api_key".to_string(), "project_id".to_string());
+        let request = &mut payload.data.request;
+        request.method = Some("POST".to_string());
+        request.url = Some("http://localhost/users?page=2&sort=name".to_string());
+        request
+            .headers
+            .insert("content-type".to_string(), "application/json".to_string());
+        request.body = Some(serde_json::json!({"name": "treblle"}));
+        payload.data.response.code = Some(404);
+        payload.data.response.load_time = Some("0.25000".to_string());
+        payload.data.response.body = Some(serde_json::json!("not found"));
+
+        let entry = har_entry(&payload);
+        assert_eq!(entry["request"]["method"], "POST");
+        assert_eq!(entry["request"]["queryString"][1]["name"], "sort");
+        assert_eq!(entry["request"]["queryString"][1]["value"], "name");
+        assert_eq!(entry["request"]["postData"]["mimeType"], "application/json");
+        assert_eq!(
+            entry["request"]["postData"]["text"],
+            r#"{"name":"treblle"}"#
+        );
+        assert_eq!(entry["response"]["status"], 404);
+        assert_eq!(entry["response"]["statusText"], "Not Found");
+        assert_eq!(entry["response"]["content"]["text"], "not found");
+        assert_eq!(entry["time"], 250.0);
+    }
+
+    #[actix_rt::test]
+    async fn keeps_entries_of_the_existing_file() {
+        let path = std::env::temp_dir().join(format!("treblle-{}.har", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let payload = |method: &str| {
+            let mut payload = TreblleData::new("api_key".to_string(), "project_id".to_string());
+            payload.data.request.method = Some(method.to_string());
+            payload
+        };
+
+        HarFile::new(&path).send(&payload("GET")).await.unwrap();
+        // Reopened like after a restart of the application
+        HarFile::new(&path).send(&payload("POST")).await.unwrap();
+
+        let log: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let entries = log["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["request"]["method"], "GET");
+        assert_eq!(entries[1]["request"]["method"], "POST");
+        let _ = std::fs::remove_file(&path);
+    }
+}
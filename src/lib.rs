@@ -44,9 +44,52 @@
 //!    .await
 //! }
 //! ```
+mod breaker;
+mod capture;
+mod compression;
+mod delivery;
+mod error;
+mod events;
 mod extractors;
+mod handle;
+mod har;
+mod media;
 mod middleware;
+mod multipart;
+mod ndjson;
+#[cfg(feature = "otlp")]
+mod otlp;
 mod payload;
+mod sampling;
+mod sink;
+mod spool;
+mod transport;
 mod treblle;
 
+pub use breaker::{CircuitBreaker, CircuitState};
+pub use compression::Compression;
+pub use delivery::{Batching, DeliveryStats, DropPolicy, Retry};
+pub use error::TreblleError;
+pub use events::LogTransport;
+#[cfg(feature = "tracing")]
+pub use events::TracingTransport;
+pub use handle::TreblleHandle;
+pub use har::{har_entry, HarFile};
+pub use ndjson::NdjsonFile;
+#[cfg(feature = "otlp")]
+pub use otlp::OtlpTransport;
+pub use payload::{
+    TreblleData, TreblleDataInner, TreblleLanguageData, TreblleRequestData, TreblleResponseData,
+    TreblleServerData, TreblleServerOsData,
+};
+pub use sampling::{ClientKey, Keep, Sampling, SamplingKey};
+pub use sink::Sink;
+pub use spool::Spool;
+pub use transport::{
+    HttpClientConfig, HttpTransport, Region, Transport, TransportError, DEFAULT_ENDPOINT,
+};
 pub use treblle::Treblle;
+
+/// Re-exported so proxies, certificates and clients can be built with the same version
+/// of reqwest that this crate uses
+pub use reqwest;
@@ -0,0 +1,89 @@
+/// Media type of a `Content-Type` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MediaType {
+    /// Type, like `application`
+    pub kind: String,
+    /// Subtype including its suffix, like `vnd.api+json`
+    pub subtype: String,
+    /// Parameters with lowercase names, like `charset` or `boundary`
+    pub params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    /// Parse the header value, matching ignores the parameters
+    pub fn parse(value: &str) -> Option<MediaType> {
+        let mut parts = value.split(';');
+        let essence = parts.next()?.trim().to_lowercase();
+        let (kind, subtype) = essence.split_once('/')?;
+        if kind.is_empty() || subtype.is_empty() {
+            return None;
+        }
+
+        let params = parts
+            .filter_map(|param| param.split_once('='))
+            .map(|(name, value)| {
+                (
+                    name.trim().to_lowercase(),
+                    value.trim().trim_matches('"').to_string(),
+                )
+            })
+            .collect();
+
+        Some(MediaType {
+            kind: kind.to_string(),
+            subtype: subtype.to_string(),
+            params,
+        })
+    }
+
+    /// Value of the parameter
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Structured syntax suffix, like `json` for `application/problem+json`
+    pub fn suffix(&self) -> Option<&str> {
+        self.subtype.rsplit_once('+').map(|(_, suffix)| suffix)
+    }
+
+    /// Check if the media type matches the pattern from the allowlist, patterns are
+    /// either exact media types like `application/json`, wildcards like `text/*`, or
+    /// structured suffixes like `+json`.
+    pub fn matches(&self, pattern: &str) -> bool {
+        let pattern = pattern.trim().to_lowercase();
+        if let Some(suffix) = pattern.strip_prefix('+') {
+            return self.suffix() == Some(suffix);
+        }
+
+        match pattern.split_once('/') {
+            Some(("*", "*")) => true,
+            Some((kind, "*")) => kind == self.kind,
+            Some((kind, subtype)) => kind == self.kind && subtype == self.subtype,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MediaType;
+
+    #[test]
+    fn matches_parameters_and_suffixes() {
+        let json = MediaType::parse("Application/JSON; charset=utf-8").unwrap();
+        assert!(json.matches("application/json"));
+
+        let problem = MediaType::parse("application/problem+json").unwrap();
+        assert!(problem.matches("+json"));
+        assert!(!problem.matches("application/json"));
+        assert!(problem.matches("application/*"));
+
+        let text = MediaType::parse("text/plain").unwrap();
+        assert!(!text.matches("+json"));
+
+        assert_eq!(MediaType::parse("json"), None);
+    }
+}
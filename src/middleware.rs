@@ -1,21 +1,28 @@
-use actix_http::{h1::Payload, HttpMessage};
+use actix_http::HttpMessage;
 use actix_web::{
+    body::BoxBody,
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
     error::Error,
     http::header,
-    web::BytesMut,
 };
 use futures::{
     future::{ok, Future, Ready},
     task::{Context, Poll},
-    StreamExt,
 };
-use serde_json::{Map, Value};
+use serde_json::Value;
 use std::cell::RefCell;
 use std::pin::Pin;
 use std::rc::Rc;
 
+use super::capture::{
+    BodyCapture, CaptureConfig, Prefix, RequestCapture, TeeBody, STREAMED_TEXT_TYPES,
+};
+use super::error::{report, ErrorHandler, TreblleError};
+use super::media::MediaType;
+use super::multipart::MultipartScanner;
 use super::payload::TreblleData;
+use super::sampling::Decision;
+use super::sink::Destination;
 use super::treblle::Treblle;
 
 impl<S: 'static> Transform<S, ServiceRequest> for Treblle
@@ -34,8 +41,10 @@ where
             project_id: self.project_id.clone(),
             api_key: self.api_key.clone(),
             debug: self.debug,
-            masking_fields: self.masking_fields.clone(),
             ignored_routes: self.ignored_routes.clone(),
+            capture: Rc::new(self.capture.clone()),
+            destinations: Rc::new(self.destinations()),
+            on_error: self.on_error.clone(),
             service: Rc::new(RefCell::new(service)),
         })
     }
@@ -45,8 +54,10 @@ pub struct TreblleMiddleware<S> {
     pub(crate) project_id: String,
     pub(crate) api_key: String,
     pub(crate) debug: bool,
-    pub(crate) masking_fields: Vec<String>,
     pub(crate) ignored_routes: Vec<String>,
+    pub(crate) capture: Rc<CaptureConfig>,
+    pub(crate) destinations: Rc<Vec<Destination>>,
+    pub(crate) on_error: Option<ErrorHandler>,
     service: Rc<RefCell<S>>,
 }
 
@@ -66,103 +77,653 @@ where
     fn call(&self, mut req: ServiceRequest) -> Self::Future {
         let skip_treblle = self
             .ignored_routes
-            .contains(&req.match_pattern().unwrap_or_else(|| "".to_string()));
+            .contains(&req.match_pattern().unwrap_or_default());
 
         // If we are skipping treblle, we will only do the call for the
         // further request and skip anything else.
         if skip_treblle {
-            let fut = self.service.call(req);
+            return Box::pin(self.service.call(req));
+        }
+
+        // Sampling is decided for every destination before the body is read, so requests
+        // that no destination wants are passed through as they are.
+        let decisions = self
+            .destinations
+            .iter()
+            .map(|destination| {
+                if !self.debug && !destination.dispatcher.accepts() {
+                    return Decision::Skipped;
+                }
 
-            return Box::pin(async move { fut.await });
+                destination
+                    .sampler
+                    .as_ref()
+                    .map(|sampler| sampler.decide(&req))
+                    .unwrap_or(Decision::Sampled(1.0))
+            })
+            .collect::<Vec<_>>();
+        if decisions.iter().all(|d| *d == Decision::Skipped) {
+            return Box::pin(self.service.call(req));
         }
+        let capture_body = decisions
+            .iter()
+            .any(|d| matches!(d, Decision::Sampled(_) | Decision::Undecided(_)));
 
         let svc = self.service.clone();
         let api_key = self.api_key.clone();
         let project_id = self.project_id.clone();
         let debug = self.debug;
-        let masking_fields = self.masking_fields.clone();
+        let destinations = self.destinations.clone();
+        let capture = self.capture.clone();
+        let on_error = self.on_error.clone();
 
         Box::pin(async move {
             let mut treblle = TreblleData::new(api_key, project_id);
-            treblle.add_request_body(get_request_body(&mut req).await?);
+            let body = capture_body.then(|| tee_request_body(&mut req, &capture, &on_error));
 
             let service_response: ServiceResponse = svc.call(req).await?;
+            match body {
+                Some(Some(body)) => treblle.add_request_body(body.finish(capture.max_bytes)),
+                Some(None) => treblle.add_request_body(Value::Null),
+                None => {}
+            }
 
-            let (service_response, mut data) = treblle.collect_data(service_response);
+            // Requests that weren't sampled are only kept if the response matches a rule,
+            // those responses are always kept so they are recorded with the rate of 1.0
+            let mut kept = vec![];
+            for (i, (destination, decision)) in destinations.iter().zip(decisions).enumerate() {
+                let sampler = match (&destination.sampler, decision) {
+                    (_, Decision::Skipped) => continue,
+                    (Some(sampler), _) => sampler,
+                    (None, _) => {
+                        kept.push((i, None, true));
+                        continue;
+                    }
+                };
 
-            // Run field masking on the data
-            data.mask_fields(masking_fields);
+                let decision = match decision {
+                    Decision::Undecided(rate) => {
+                        sampler.decide_late(service_response.request(), rate)
+                    }
+                    _ => decision,
+                };
+                let with_body = matches!(decision, Decision::Sampled(_));
+                if sampler.keep(&service_response) {
+                    kept.push((i, Some(1.0), with_body));
+                } else if let Decision::Sampled(rate) = decision {
+                    kept.push((i, Some(rate), with_body));
+                }
+            }
+            if kept.is_empty() {
+                return Ok(service_response);
+            }
 
-            if debug {
-                log::debug!("Treblle payload data:\n{:#?}", &data);
-                data.send_debug().await;
-            } else {
-                data.send();
+            let (service_response, mut data) = treblle.collect_data(service_response);
+            if data.data.response.body.is_some() {
+                deliver(&destinations, kept, data, debug, on_error.as_ref()).await;
+                return Ok(service_response);
             }
 
-            Ok(service_response)
+            // Bodies that stream are copied while they are sent, their payload is only
+            // delivered once the body ended and counts as pending until then
+            let reserved = kept
+                .iter()
+                .map(|(i, _, _)| destinations[*i].dispatcher.reserve())
+                .collect::<Vec<_>>();
+            Ok(service_response.map_body(move |head, body| {
+                let text = head
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(MediaType::parse)
+                    .map(|media_type| STREAMED_TEXT_TYPES.iter().any(|t| media_type.matches(t)))
+                    .unwrap_or(false);
+                let max_bytes = match text {
+                    true => capture.response_max_bytes,
+                    false => 0,
+                };
+
+                BoxBody::new(TeeBody::new(body, max_bytes, move |body, size| {
+                    data.add_response_body(text.then_some(body), size);
+                    actix_web::rt::spawn(async move {
+                        deliver(&destinations, kept, data, debug, on_error.as_ref()).await;
+                        drop(reserved);
+                    });
+                }))
+            }))
         })
     }
 }
 
-/// Clone and extract any type of body received from the request into a Value type
-/// that is universal JSON holder. If the deserialization of the request data fails, we'll treat
-/// it as a Null.
-async fn get_request_body(sr: &mut ServiceRequest) -> Result<Value, Error> {
-    let content_type = sr
+/// Mask the payload for every destination that kept it and deliver it, `kept` holds the
+/// index of the destination, its sample rate and if it captured the request body
+async fn deliver(
+    destinations: &[Destination],
+    kept: Vec<(usize, Option<f64>, bool)>,
+    data: TreblleData,
+    debug: bool,
+    on_error: Option<&ErrorHandler>,
+) {
+    let mut data = Some(data);
+    let last = kept.len() - 1;
+    for (i, (destination, sample_rate, with_body)) in kept.into_iter().enumerate() {
+        let destination = &destinations[destination];
+        let mut data = match i == last {
+            true => data.take().unwrap(),
+            false => data.as_ref().unwrap().clone(),
+        };
+        data.sample_rate = sample_rate;
+        if !with_body {
+            data.data.request.body = None;
+        }
+
+        // Run field masking on the data
+        if let Err(e) = data.mask_fields(destination.masking_fields.clone()) {
+            report(on_error, e);
+        }
+
+        if debug {
+            log::debug!("Treblle payload data:\n{:#?}", &data);
+            if let Err(e) = destination.dispatcher.transport().send(&data).await {
+                let e = TreblleError::from(e);
+                log::error!("{}", e);
+                report(on_error, e);
+            }
+        } else {
+            destination.dispatcher.enqueue(data).await;
+        }
+    }
+}
+
+/// Wrap the payload of the request so the body is captured while the handler reads it,
+/// the handler gets every chunk exactly as it came and nothing is read ahead of it.
+/// The part of the body the handler doesn't read is copied after the response.
+fn tee_request_body(
+    sr: &mut ServiceRequest,
+    capture: &CaptureConfig,
+    on_error: &Option<ErrorHandler>,
+) -> Option<RequestCapture<actix_http::Payload>> {
+    let media_type = sr
         .headers()
         .get(header::CONTENT_TYPE)
-        .map(|v| v.clone().to_str().unwrap_or("").to_string())
-        .unwrap_or_else(|| "".to_string())
-        .to_lowercase();
-
-    // TODO: Content type that is not application json won't be logged since it can cause
-    // harm in some setups, this might be a feature to implement sometimes in the future,
-    // once we get a proper chance to test it and figure out all the bugs that keep happening,
-    // but for now we will simply set it as Null value in the log.
-    //
-    // Issue that we got was that some multipart forms weren't recognized properly after
-    // the things we did here below to them, the issue couldn't be reproduced in a local
-    // setting, but it was happening within the cluster.
-    //
-    // Payload would apear okay in treblle.com, but later methods that were supposed
-    // to handle that payload reported invalid multipart data, or form data.
-    if content_type != "application/json" {
-        return Ok(Value::Null);
-    }
-
-    let mut request_body = BytesMut::new();
-    while let Some(chunk) = sr.take_payload().next().await {
-        request_body.extend_from_slice(&chunk?);
-    }
-    let bytes = request_body.freeze();
-
-    let (_sender, mut orig_payload) = Payload::create(true);
-    orig_payload.unread_data(bytes.clone());
-    sr.set_payload(actix_http::Payload::from(orig_payload));
-
-    if bytes.is_empty() {
-        return Ok(Value::Null);
-    }
-
-    Ok(match serde_json::from_slice::<Value>(&bytes) {
-        Ok(v) => v,
-        Err(_) => match String::from_utf8(bytes.to_vec()) {
-            Ok(s) => {
-                let mut map = Map::new();
-                map.insert("request_as_a_string".to_string(), Value::String(s));
-
-                Value::Object(map)
-            }
-            Err(_) => {
-                let mut map = Map::new();
-                map.insert(
-                    "request_as_raw_bytes".to_string(),
-                    Value::String(format!("{:?}", bytes)),
-                );
-
-                Value::Object(map)
+        .and_then(|v| v.to_str().ok())
+        .and_then(MediaType::parse);
+
+    // Content types that aren't on the allowlist won't be logged since it can cause
+    // harm in some setups, they are simply set as Null value in the log.
+    let media_type = match media_type {
+        Some(media_type) if capture.content_types.iter().any(|t| media_type.matches(t)) => {
+            media_type
+        }
+        _ => return None,
+    };
+
+    let body = match media_type.matches("multipart/form-data") {
+        true => match media_type.param("boundary") {
+            Some(boundary) if !boundary.is_empty() => {
+                BodyCapture::Multipart(MultipartScanner::new(boundary, capture.multipart_text))
             }
+            _ => return None,
         },
-    })
+        false => BodyCapture::Prefix(Prefix::new(media_type, capture.max_bytes)),
+    };
+
+    let (body, tee) = RequestCapture::tee(body, sr.take_payload(), on_error.clone());
+    sr.set_payload(actix_http::Payload::Stream {
+        payload: Box::pin(tee),
+    });
+
+    Some(body)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Keep, Sampling, Sink, Transport, TransportError, Treblle, TreblleData};
+    use actix_web::{test, web, App, HttpResponse};
+    use futures::future::BoxFuture;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct MemoryTransport {
+        payloads: Arc<Mutex<Vec<serde_json::Value>>>,
+    }
+
+    impl Transport for MemoryTransport {
+        fn send<'a>(
+            &'a self,
+            payload: &'a TreblleData,
+        ) -> BoxFuture<'a, Result<(), TransportError>> {
+            Box::pin(async move {
+                let value = serde_json::to_value(payload)?;
+                self.payloads.lock().unwrap().push(value);
+                Ok(())
+            })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn delivers_payload_through_transport() {
+        let transport = MemoryTransport::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    Treblle::new("project_id".to_string(), "api_key".to_string())
+                        .debug()
+                        .transport(transport.clone()),
+                )
+                .route("/hello", web::post().to(|| async { "Hello World!" })),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/hello")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(r#"{"password":"secret","name":"treblle"}"#)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+
+        let payloads = transport.payloads.lock().unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0]["api_key"], "api_key");
+        assert_eq!(payloads[0]["data"]["request"]["body"]["password"], "******");
+        assert_eq!(payloads[0]["data"]["request"]["body"]["name"], "treblle");
+        assert_eq!(payloads[0]["data"]["response"]["body"], "Hello World!");
+    }
+
+    #[actix_rt::test]
+    async fn keeps_server_errors_that_were_not_sampled() {
+        let transport = MemoryTransport::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    Treblle::new("project_id".to_string(), "api_key".to_string())
+                        .debug()
+                        .transport(transport.clone())
+                        .sampling(Sampling::new(0.0).keep(Keep::ServerErrors)),
+                )
+                .route("/hello", web::post().to(|| async { "Hello World!" }))
+                .route(
+                    "/fail",
+                    web::post().to(|| async { HttpResponse::InternalServerError().finish() }),
+                ),
+        )
+        .await;
+
+        for uri in ["/hello", "/fail"] {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(("content-type", "application/json"))
+                .set_payload(r#"{"name":"treblle"}"#)
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let payloads = transport.payloads.lock().unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0]["data"]["response"]["code"], 500);
+        assert_eq!(payloads[0]["sample_rate"], 1.0);
+        assert!(payloads[0]["data"]["request"]["body"].is_null());
+    }
+
+    struct FailingTransport;
+
+    impl Transport for FailingTransport {
+        fn send<'a>(
+            &'a self,
+            _payload: &'a TreblleData,
+        ) -> BoxFuture<'a, Result<(), TransportError>> {
+            Box::pin(async { Err(TransportError::Other("unreachable".into())) })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn fans_out_to_sinks_with_their_own_masking() {
+        let transport = MemoryTransport::default();
+        let audit = MemoryTransport::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    Treblle::new("project_id".to_string(), "api_key".to_string())
+                        .debug()
+                        .transport(transport.clone())
+                        .sink(Sink::new("failing", FailingTransport))
+                        .sink(Sink::new("audit", audit.clone()).clear_masking_fields()),
+                )
+                .route("/hello", web::post().to(|| async { "Hello World!" })),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/hello")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(r#"{"password":"secret"}"#)
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let payloads = transport.payloads.lock().unwrap();
+        assert_eq!(payloads[0]["data"]["request"]["body"]["password"], "******");
+        let audited = audit.payloads.lock().unwrap();
+        assert_eq!(audited.len(), 1);
+        assert_eq!(audited[0]["data"]["request"]["body"]["password"], "secret");
+    }
+
+    #[actix_rt::test]
+    async fn captures_json_media_types_with_parameters_and_suffixes() {
+        let transport = MemoryTransport::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    Treblle::new("project_id".to_string(), "api_key".to_string())
+                        .debug()
+                        .transport(transport.clone()),
+                )
+                .route("/hello", web::post().to(|| async { "Hello World!" })),
+        )
+        .await;
+
+        for content_type in [
+            "application/json; charset=utf-8",
+            "application/problem+json",
+            "text/plain",
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/hello")
+                .insert_header(("content-type", content_type))
+                .set_payload(r#"{"name":"treblle"}"#)
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let payloads = transport.payloads.lock().unwrap();
+        assert_eq!(payloads[0]["data"]["request"]["body"]["name"], "treblle");
+        assert_eq!(payloads[1]["data"]["request"]["body"]["name"], "treblle");
+        assert!(payloads[2]["data"]["request"]["body"].is_null());
+    }
+
+    #[actix_rt::test]
+    async fn captures_urlencoded_forms_and_restores_them() {
+        #[derive(serde::Deserialize)]
+        struct Login {
+            user: String,
+            password: String,
+        }
+
+        let transport = MemoryTransport::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    Treblle::new("project_id".to_string(), "api_key".to_string())
+                        .debug()
+                        .transport(transport.clone()),
+                )
+                .route(
+                    "/login",
+                    web::post().to(|form: web::Form<Login>| async move {
+                        format!("{}:{}", form.user, form.password)
+                    }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .insert_header(("content-type", "application/x-www-form-urlencoded"))
+            .set_payload("user=tre+blle&password=s%3Dcret&tag=a&tag=b")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "tre blle:s=cret");
+
+        let payloads = transport.payloads.lock().unwrap();
+        let form = &payloads[0]["data"]["request"]["body"];
+        assert_eq!(form["user"], "tre blle");
+        assert_eq!(form["password"], "******");
+        assert_eq!(form["tag"], serde_json::json!(["a", "b"]));
+    }
+
+    #[actix_rt::test]
+    async fn truncates_bodies_over_the_limit_without_changing_them() {
+        let transport = MemoryTransport::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    Treblle::new("project_id".to_string(), "api_key".to_string())
+                        .debug()
+                        .transport(transport.clone())
+                        .request_body_limit(8),
+                )
+                .route("/echo", web::post().to(|body: String| async move { body })),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(r#"{"name":"treblle"}"#)
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, r#"{"name":"treblle"}"#);
+
+        let payloads = transport.payloads.lock().unwrap();
+        let captured = &payloads[0]["data"]["request"]["body"];
+        assert_eq!(captured["request_as_a_string"], r#"{"name":"#);
+        assert_eq!(captured["truncated"], true);
+        assert_eq!(captured["size"], 18);
+    }
+
+    #[actix_rt::test]
+    async fn reports_request_bodies_that_failed_to_read() {
+        let transport = MemoryTransport::default();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let reported = errors.clone();
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    Treblle::new("project_id".to_string(), "api_key".to_string())
+                        .debug()
+                        .transport(transport.clone())
+                        .on_error(move |e| reported.lock().unwrap().push(e.to_string())),
+                )
+                .route("/echo", web::post().to(|body: String| async move { body })),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header(("content-type", "application/json"))
+            .to_request();
+        let chunks = [
+            Ok(web::Bytes::from_static(b"{\"name\"")),
+            Err(actix_web::error::PayloadError::Incomplete(None)),
+        ];
+        let payload: actix_http::BoxedPayloadStream = Box::pin(futures::stream::iter(chunks));
+        let (req, _) = req.replace_payload(actix_http::Payload::Stream { payload });
+
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_client_error());
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Treblle couldn't capture the request"));
+        let payloads = transport.payloads.lock().unwrap();
+        assert_eq!(
+            payloads[0]["data"]["request"]["body"]["request_as_a_string"],
+            "{\"name\""
+        );
+    }
+
+    #[actix_rt::test]
+    async fn does_not_wait_for_bodies_the_handler_did_not_read() {
+        let transport = MemoryTransport::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    Treblle::new("project_id".to_string(), "api_key".to_string())
+                        .debug()
+                        .transport(transport.clone()),
+                )
+                .route(
+                    "/upload",
+                    web::post().to(|| async { HttpResponse::Unauthorized().finish() }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("content-type", "application/json"))
+            .to_request();
+        let arrived = futures::stream::iter([Ok(web::Bytes::from_static(b"{\"name\""))]);
+        let payload: actix_http::BoxedPayloadStream = Box::pin(futures::StreamExt::chain(
+            arrived,
+            futures::stream::pending(),
+        ));
+        let (req, _) = req.replace_payload(actix_http::Payload::Stream { payload });
+
+        let res = tokio::time::timeout(Duration::from_secs(1), test::call_service(&app, req))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 401);
+
+        let payloads = transport.payloads.lock().unwrap();
+        let captured = &payloads[0]["data"]["request"]["body"];
+        assert_eq!(captured["request_as_a_string"], "{\"name\"");
+        assert_eq!(captured["truncated"], true);
+        assert_eq!(captured["size"], 7);
+    }
+
+    #[actix_rt::test]
+    async fn captures_streaming_responses_once_they_ended() {
+        let transport = MemoryTransport::default();
+        let treblle = Treblle::new("project_id".to_string(), "api_key".to_string())
+            .debug()
+            .transport(transport.clone())
+            .response_body_limit(16);
+        let handle = treblle.handle();
+        let stream = |content_type: &'static str| {
+            let chunks = ["data: one\n\n", "data: two\n\n", "data: three\n\n"]
+                .map(|chunk| Ok::<_, actix_web::Error>(web::Bytes::from(chunk)));
+
+            HttpResponse::Ok()
+                .content_type(content_type)
+                .streaming(futures::stream::iter(chunks))
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(treblle)
+                .route(
+                    "/events",
+                    web::get().to(move || async move { stream("text/event-stream") }),
+                )
+                .route(
+                    "/download",
+                    web::get().to(move || async move { stream("application/octet-stream") }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/events").to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(transport.payloads.lock().unwrap().is_empty());
+        assert!(!handle.flush(Duration::from_millis(10)).await);
+
+        let body = test::read_body(res).await;
+        assert_eq!(body, "data: one\n\ndata: two\n\ndata: three\n\n");
+        assert!(handle.flush(Duration::from_secs(1)).await);
+
+        let req = test::TestRequest::get().uri("/download").to_request();
+        test::call_and_read_body(&app, req).await;
+        assert!(handle.flush(Duration::from_secs(1)).await);
+
+        let payloads = transport.payloads.lock().unwrap();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(
+            payloads[0]["data"]["response"]["body"],
+            "data: one\n\ndata:"
+        );
+        assert_eq!(payloads[0]["data"]["response"]["size"], 35);
+        assert!(payloads[1]["data"]["response"]["body"].is_null());
+        assert_eq!(payloads[1]["data"]["response"]["size"], 35);
+    }
+
+    const MULTIPART: &str = "--XyZ\r\n\
+        Content-Disposition: form-data; name=\"user\"\r\n\r\n\
+        treblle\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"password\"\r\n\r\n\
+        secret\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"avatar\"; filename=\"me.png\"\r\n\
+        Content-Type: image/png\r\n\r\n\
+        \x7fPNG\r\n\r\n--not-the-boundary\r\n--XyZ--\r\n";
+
+    /// Handler that echoes every field it parsed with actix-multipart
+    async fn echo_multipart(
+        mut form: actix_multipart::Multipart,
+    ) -> Result<String, actix_web::Error> {
+        use futures::StreamExt;
+
+        let mut fields = vec![];
+        while let Some(field) = form.next().await {
+            let mut field = field?;
+            let name = field.name().unwrap_or_default().to_string();
+            let mut content = vec![];
+            while let Some(chunk) = field.next().await {
+                content.extend_from_slice(&chunk?);
+            }
+            fields.push(format!("{}={}", name, String::from_utf8_lossy(&content)));
+        }
+
+        Ok(fields.join("&"))
+    }
+
+    #[actix_rt::test]
+    async fn multipart_round_trips_through_actix_multipart() {
+        let transport = MemoryTransport::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    Treblle::new("project_id".to_string(), "api_key".to_string())
+                        .debug()
+                        .transport(transport.clone())
+                        .multipart_text_fields(64),
+                )
+                .route("/upload", web::post().to(echo_multipart)),
+        )
+        .await;
+
+        // Small chunks so boundaries are split between them
+        for chunk_size in [1, 7, MULTIPART.len()] {
+            let req = test::TestRequest::post()
+                .uri("/upload")
+                .insert_header(("content-type", "multipart/form-data; boundary=\"XyZ\""))
+                .to_request();
+            let chunks = MULTIPART
+                .as_bytes()
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    Ok::<_, actix_web::error::PayloadError>(web::Bytes::copy_from_slice(chunk))
+                })
+                .collect::<Vec<_>>();
+            let payload: actix_http::BoxedPayloadStream = Box::pin(futures::stream::iter(chunks));
+            let (req, _) = req.replace_payload(actix_http::Payload::Stream { payload });
+
+            let body = test::call_and_read_body(&app, req).await;
+            assert_eq!(
+                body,
+                "user=treblle&password=secret&avatar=\x7fPNG\r\n\r\n--not-the-boundary"
+            );
+        }
+
+        let payloads = transport.payloads.lock().unwrap();
+        assert_eq!(payloads.len(), 3);
+        for payload in payloads.iter() {
+            let form = &payload["data"]["request"]["body"];
+            assert_eq!(form["fields"]["user"], "treblle");
+            assert_eq!(form["fields"]["password"], "******");
+            assert_eq!(form["parts"][2]["filename"], "me.png");
+            assert_eq!(form["parts"][2]["content_type"], "image/png");
+            assert_eq!(form["parts"][2]["size"], 26);
+            assert!(form.get("complete").is_none());
+        }
+    }
 }
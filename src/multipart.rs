@@ -0,0 +1,303 @@
+use serde_json::{json, Map, Value};
+
+/// Longest header block of a part that is accepted
+const MAX_HEADERS: usize = 8 * 1024;
+
+/// Where the scanner is in the multipart body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Before the first delimiter
+    Preamble,
+    /// Right after a delimiter, either the closing `--` or the end of the line follows
+    Delimiter,
+    /// Headers of a part
+    Headers,
+    /// Content of a part
+    Body,
+    /// After the closing delimiter
+    End,
+    /// Body isn't valid multipart, nothing more is scanned
+    Invalid,
+}
+
+/// Metadata of a single part
+#[derive(Debug, Default)]
+struct Part {
+    name: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+    size: u64,
+    text: Vec<u8>,
+}
+
+/// Scans a `multipart/form-data` body chunk by chunk and records the names, content
+/// types, filenames and sizes of its parts, without keeping the content of the parts.
+///
+/// Text fields, parts without a filename, are kept as well if they aren't longer than
+/// `max_text` bytes.
+pub(crate) struct MultipartScanner {
+    /// `\r\n--boundary`, the first delimiter is found by starting with `\r\n`
+    delimiter: Vec<u8>,
+    /// Bytes that couldn't be scanned yet because they might be a part of a delimiter
+    buf: Vec<u8>,
+    state: State,
+    parts: Vec<Part>,
+    max_text: usize,
+}
+
+impl MultipartScanner {
+    pub fn new(boundary: &str, max_text: usize) -> MultipartScanner {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        MultipartScanner {
+            delimiter,
+            buf: b"\r\n".to_vec(),
+            state: State::Preamble,
+            parts: vec![],
+            max_text,
+        }
+    }
+
+    /// Scan the next chunk of the body
+    pub fn feed(&mut self, chunk: &[u8]) {
+        if matches!(self.state, State::End | State::Invalid) {
+            return;
+        }
+
+        self.buf.extend_from_slice(chunk);
+        while self.step() {}
+    }
+
+    /// Advance through the buffered bytes, returns `false` once more bytes are needed
+    fn step(&mut self) -> bool {
+        match self.state {
+            State::Preamble | State::Body => match find(&self.buf, &self.delimiter) {
+                Some(i) => {
+                    self.consume(i);
+                    self.buf.drain(..self.delimiter.len());
+                    self.state = State::Delimiter;
+                    true
+                }
+                None => {
+                    // Tail of the buffer might be the start of a delimiter
+                    let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+                    self.consume(safe);
+                    false
+                }
+            },
+            State::Delimiter => {
+                if self.buf.starts_with(b"--") {
+                    self.state = State::End;
+                    self.buf.clear();
+                    return false;
+                }
+
+                match find(&self.buf, b"\r\n") {
+                    Some(i) => {
+                        self.buf.drain(..i + 2);
+                        self.state = State::Headers;
+                        true
+                    }
+                    None => {
+                        if self.buf.len() > MAX_HEADERS {
+                            self.invalid();
+                        }
+                        false
+                    }
+                }
+            }
+            State::Headers => {
+                let end = match self.buf.starts_with(b"\r\n") {
+                    true => Some(0),
+                    false => find(&self.buf, b"\r\n\r\n").map(|i| i + 2),
+                };
+
+                match end {
+                    Some(end) => {
+                        let part = parse_headers(&self.buf[..end]);
+                        self.parts.push(part);
+                        self.buf.drain(..end + 2);
+                        self.state = State::Body;
+                        true
+                    }
+                    None => {
+                        if self.buf.len() > MAX_HEADERS {
+                            self.invalid();
+                        }
+                        false
+                    }
+                }
+            }
+            State::End | State::Invalid => false,
+        }
+    }
+
+    /// Count the first `len` bytes of the buffer towards the current part and drop them
+    fn consume(&mut self, len: usize) {
+        if self.state == State::Body {
+            if let Some(part) = self.parts.last_mut() {
+                part.size += len as u64;
+                if part.filename.is_none() && part.text.len() <= self.max_text {
+                    let take = len.min(self.max_text + 1 - part.text.len());
+                    part.text.extend_from_slice(&self.buf[..take]);
+                }
+            }
+        }
+
+        self.buf.drain(..len);
+    }
+
+    fn invalid(&mut self) {
+        self.state = State::Invalid;
+        self.buf = vec![];
+    }
+
+    /// Metadata of the parts seen so far, text fields are collected under `fields` the
+    /// same way as urlencoded forms so they get masked
+    pub fn finish(&self) -> Value {
+        let mut fields = Map::new();
+        let mut parts = vec![];
+
+        for part in &self.parts {
+            let mut meta = Map::new();
+            meta.insert("name".to_string(), json!(part.name));
+            if let Some(filename) = &part.filename {
+                meta.insert("filename".to_string(), json!(filename));
+            }
+            if let Some(content_type) = &part.content_type {
+                meta.insert("content_type".to_string(), json!(content_type));
+            }
+            meta.insert("size".to_string(), json!(part.size));
+            parts.push(Value::Object(meta));
+
+            let text = match (&part.name, &part.filename) {
+                (Some(name), None) if part.text.len() <= self.max_text && self.max_text > 0 => {
+                    match std::str::from_utf8(&part.text) {
+                        Ok(text) => Some((name, text)),
+                        Err(_) => None,
+                    }
+                }
+                _ => None,
+            };
+            if let Some((name, text)) = text {
+                let value = Value::String(text.to_string());
+                match fields.get_mut(name) {
+                    Some(Value::Array(values)) => values.push(value),
+                    Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+                    None => {
+                        fields.insert(name.clone(), value);
+                    }
+                }
+            }
+        }
+
+        let mut body = Map::new();
+        body.insert("fields".to_string(), Value::Object(fields));
+        body.insert("parts".to_string(), Value::Array(parts));
+        if self.state != State::End {
+            body.insert("complete".to_string(), Value::Bool(false));
+        }
+
+        Value::Object(body)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Read the name and filename from `Content-Disposition` and the `Content-Type` header
+fn parse_headers(block: &[u8]) -> Part {
+    let mut part = Part::default();
+
+    for line in String::from_utf8_lossy(block).split("\r\n") {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+            None => continue,
+        };
+
+        match name.as_str() {
+            "content-disposition" => {
+                for param in split_params(value) {
+                    match param.split_once('=') {
+                        Some((key, value)) if key.trim().eq_ignore_ascii_case("name") => {
+                            part.name = Some(unquote(value));
+                        }
+                        Some((key, value)) if key.trim().eq_ignore_ascii_case("filename") => {
+                            part.filename = Some(unquote(value));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            "content-type" => part.content_type = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    part
+}
+
+/// Split the header value on semicolons that aren't inside of quotes
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(&value[start..]);
+
+    params
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+        .replace("\\\"", "\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::MultipartScanner;
+
+    #[test]
+    fn scans_parts_split_across_chunks() {
+        let body = "preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"user\"\r\n\r\n\
+            treblle\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"avatar\"; filename=\"me; really.png\"\r\n\
+            Content-Type: image/png\r\n\r\n\
+            \x7fPNG--XyZ-not-a-delimiter\r\n--XyZ--\r\n";
+
+        // Every possible split of the body into chunks gives the same result
+        for chunk_size in 1..body.len() {
+            let mut scanner = MultipartScanner::new("XyZ", 16);
+            for chunk in body.as_bytes().chunks(chunk_size) {
+                scanner.feed(chunk);
+            }
+
+            let meta = scanner.finish();
+            assert_eq!(meta["fields"]["user"], "treblle");
+            assert_eq!(meta["parts"][0]["size"], 7);
+            assert_eq!(meta["parts"][1]["name"], "avatar");
+            assert_eq!(meta["parts"][1]["filename"], "me; really.png");
+            assert_eq!(meta["parts"][1]["content_type"], "image/png");
+            assert_eq!(meta["parts"][1]["size"], 25);
+            assert!(meta.get("complete").is_none());
+        }
+    }
+}
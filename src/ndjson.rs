@@ -0,0 +1,204 @@
+use futures::future::BoxFuture;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::payload::TreblleData;
+use crate::transport::{Transport, TransportError};
+
+/// Transport that appends every payload as a line of JSON to a file, in the same shape
+/// that is sent to Treblle.com, so the captures can be inspected with `jq` in
+/// environments without egress.
+///
+/// The file is rotated once it grows over `max_bytes` or once it has been written to
+/// for `max_age`. Rotated files get a number appended, `.1` being the newest, and only
+/// the `keep` newest ones are kept around.
+///
+/// ```rust,ignore
+/// let file = actix_treblle::NdjsonFile::new("/var/log/treblle/payloads.ndjson")
+///     .max_bytes(64 * 1024 * 1024)
+///     .max_age(std::time::Duration::from_secs(24 * 60 * 60))
+///     .keep(7);
+///
+/// let treblle = actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+///     .sink(actix_treblle::Sink::new("file", file));
+/// ```
+pub struct NdjsonFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+    state: Mutex<State>,
+}
+
+/// File that is currently written to
+struct State {
+    file: Option<File>,
+    bytes: u64,
+    opened_at: Instant,
+}
+
+impl NdjsonFile {
+    /// Append payloads to the file at the given path, it will be created if it doesn't
+    /// exist. By default the file is rotated at 100MB and 5 old files are kept.
+    pub fn new<P: Into<PathBuf>>(path: P) -> NdjsonFile {
+        NdjsonFile {
+            path: path.into(),
+            max_bytes: 100 * 1024 * 1024,
+            max_age: None,
+            keep: 5,
+            state: Mutex::new(State {
+                file: None,
+                bytes: 0,
+                opened_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Size after which the file is rotated
+    pub fn max_bytes(mut self, max_bytes: u64) -> NdjsonFile {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Time after which the file is rotated, counted from when it was opened
+    pub fn max_age(mut self, max_age: Duration) -> NdjsonFile {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Number of rotated files to keep, older ones are deleted
+    pub fn keep(mut self, keep: usize) -> NdjsonFile {
+        self.keep = keep;
+        self
+    }
+
+    fn write(&self, payloads: &[TreblleData]) -> Result<(), TransportError> {
+        let mut lines = vec![];
+        for payload in payloads {
+            serde_json::to_writer(&mut lines, payload)?;
+            lines.push(b'\n');
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.file.is_none() {
+            self.open(&mut state)?;
+        }
+
+        let expired = self
+            .max_age
+            .map(|max_age| state.opened_at.elapsed() >= max_age)
+            .unwrap_or(false);
+        let full = state.bytes > 0 && state.bytes + lines.len() as u64 > self.max_bytes;
+        if expired || full {
+            state.file = None;
+            self.rotate()?;
+            self.open(&mut state)?;
+        }
+
+        if let Some(file) = state.file.as_mut() {
+            file.write_all(&lines)?;
+            file.flush()?;
+        }
+        state.bytes += lines.len() as u64;
+
+        Ok(())
+    }
+
+    fn open(&self, state: &mut State) -> io::Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        state.bytes = file.metadata()?.len();
+        state.opened_at = Instant::now();
+        state.file = Some(file);
+
+        Ok(())
+    }
+
+    /// Shift the rotated files by one and move the current file in place of the newest
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return remove_file(&self.path);
+        }
+
+        remove_file(&self.rotated(self.keep))?;
+        for n in (1..self.keep).rev() {
+            rename(&self.rotated(n), &self.rotated(n + 1))?;
+        }
+        rename(&self.path, &self.rotated(1))
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+}
+
+impl Transport for NdjsonFile {
+    fn send<'a>(&'a self, payload: &'a TreblleData) -> BoxFuture<'a, Result<(), TransportError>> {
+        Box::pin(async move { self.write(std::slice::from_ref(payload)) })
+    }
+
+    fn send_batch<'a>(
+        &'a self,
+        payloads: &'a [TreblleData],
+    ) -> BoxFuture<'a, Result<(), (usize, TransportError)>> {
+        Box::pin(async move { self.write(payloads).map_err(|e| (0, e)) })
+    }
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NdjsonFile;
+    use crate::payload::TreblleData;
+    use crate::transport::Transport;
+
+    #[actix_rt::test]
+    async fn rotates_by_size_and_keeps_old_files() {
+        let dir = std::env::temp_dir().join(format!("treblle-ndjson-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("payloads.ndjson");
+        let file = NdjsonFile::new(&path).max_bytes(1).keep(2);
+
+        for project_id in ["first", "second", "third", "fourth"] {
+            let payload = TreblleData::new("api_key".to_string(), project_id.to_string());
+            file.send(&payload).await.unwrap();
+        }
+
+        let read = |name: &str| {
+            let content = std::fs::read_to_string(dir.join(name)).unwrap();
+            let lines = content.lines().collect::<Vec<_>>();
+            assert_eq!(lines.len(), 1);
+            serde_json::from_str::<serde_json::Value>(lines[0]).unwrap()["project_id"].clone()
+        };
+        assert_eq!(read("payloads.ndjson"), "fourth");
+        assert_eq!(read("payloads.ndjson.1"), "third");
+        assert_eq!(read("payloads.ndjson.2"), "second");
+        assert!(!dir.join("payloads.ndjson.3").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
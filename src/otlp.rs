@@ -0,0 +1,348 @@
+use futures::future::BoxFuture;
+use rand::Rng;
+use serde_json::{json, Value};
+
+use crate::payload::TreblleData;
+use crate::transport::{parse_retry_after, HttpClientConfig, Transport, TransportError};
+
+/// Transport that exports every payload as an OpenTelemetry server span to an OTLP
+/// collector, using OTLP/HTTP with JSON encoding.
+///
+/// Spans follow the HTTP semantic conventions: `http.request.method`, `url.full`,
+/// `url.path`, `url.query`, `url.scheme`, `server.address`, `client.address`,
+/// `user_agent.original`, `http.response.status_code` and the body sizes. Responses
+/// with 5xx status codes get the error status and `error.type`, captured errors are
+/// added as `exception` events. When the request has a `traceparent` header the span
+/// joins that trace.
+///
+/// Payloads are masked before they get to the sink, so masked headers stay masked
+/// when they are exported with [`OtlpTransport::headers`].
+///
+/// ```rust,ignore
+/// let otlp = actix_treblle::OtlpTransport::new("http://127.0.0.1:4318")?
+///     .service_name("users-api")
+///     .header("x-tenant", "acme");
+///
+/// let treblle = actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+///     .sink(actix_treblle::Sink::new("otlp", otlp).batching(Default::default()));
+/// ```
+pub struct OtlpTransport {
+    client: reqwest::Client,
+    url: String,
+    service_name: String,
+    headers: Vec<(String, String)>,
+    export_headers: bool,
+}
+
+impl OtlpTransport {
+    /// Export spans to the collector at the given base URL, `/v1/traces` is appended to it.
+    /// Exports time out like the ones of [`crate::HttpTransport`], see [`HttpClientConfig`],
+    /// fails if that client can't be built.
+    pub fn new<E: AsRef<str>>(endpoint: E) -> reqwest::Result<OtlpTransport> {
+        Ok(OtlpTransport {
+            client: HttpClientConfig::default().build()?,
+            url: format!("{}/v1/traces", endpoint.as_ref().trim_end_matches('/')),
+            service_name: "unknown_service".to_string(),
+            headers: vec![],
+            export_headers: false,
+        })
+    }
+
+    /// Use your own HTTP client
+    pub fn client(mut self, client: reqwest::Client) -> OtlpTransport {
+        self.client = client;
+        self
+    }
+
+    /// Value of the `service.name` resource attribute
+    pub fn service_name<N: Into<String>>(mut self, service_name: N) -> OtlpTransport {
+        self.service_name = service_name.into();
+        self
+    }
+
+    /// Header sent to the collector with every export, for example for authentication
+    pub fn header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> OtlpTransport {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Add the request and response headers as `http.request.header.*` and
+    /// `http.response.header.*` attributes
+    pub fn headers(mut self) -> OtlpTransport {
+        self.export_headers = true;
+        self
+    }
+
+    async fn export(&self, payloads: &[TreblleData]) -> Result<(), TransportError> {
+        let spans = payloads
+            .iter()
+            .map(|payload| span(payload, self.export_headers))
+            .collect::<Vec<_>>();
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        attribute("service.name", &self.service_name),
+                        attribute("telemetry.sdk.name", env!("CARGO_PKG_NAME")),
+                        attribute("telemetry.sdk.language", "rust"),
+                        attribute("telemetry.sdk.version", env!("CARGO_PKG_VERSION")),
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                    "spans": spans,
+                }],
+            }],
+        });
+
+        let mut req = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        for (name, value) in &self.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+
+        let res = req.body(serde_json::to_vec(&body)?).send().await?;
+        if !res.status().is_success() {
+            let code = res.status().as_u16();
+            let retry_after = res
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after);
+            let body = res.text().await.unwrap_or_default();
+
+            return Err(TransportError::Status {
+                code,
+                body,
+                retry_after,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Transport for OtlpTransport {
+    fn send<'a>(&'a self, payload: &'a TreblleData) -> BoxFuture<'a, Result<(), TransportError>> {
+        Box::pin(self.export(std::slice::from_ref(payload)))
+    }
+
+    fn send_batch<'a>(
+        &'a self,
+        payloads: &'a [TreblleData],
+    ) -> BoxFuture<'a, Result<(), (usize, TransportError)>> {
+        Box::pin(async move { self.export(payloads).await.map_err(|e| (0, e)) })
+    }
+}
+
+/// Convert the payload into an OTLP span
+fn span(payload: &TreblleData, export_headers: bool) -> Value {
+    let request = &payload.data.request;
+    let response = &payload.data.response;
+
+    let method = request.method.clone().unwrap_or_default();
+    let start = payload.start.timestamp_nanos_opt().unwrap_or_default();
+    let duration = response
+        .load_time
+        .as_deref()
+        .and_then(|load_time| load_time.parse::<f64>().ok())
+        .map(|seconds| (seconds * 1e9) as i64)
+        .unwrap_or_default();
+
+    let mut attributes = vec![attribute("http.request.method", &method)];
+    if let Some(url) = &request.url {
+        attributes.push(attribute("url.full", url));
+        if let Ok(url) = reqwest::Url::parse(url) {
+            attributes.push(attribute("url.scheme", url.scheme()));
+            attributes.push(attribute("url.path", url.path()));
+            if let Some(query) = url.query() {
+                attributes.push(attribute("url.query", query));
+            }
+            if let Some(host) = url.host_str() {
+                attributes.push(attribute("server.address", host));
+            }
+            if let Some(port) = url.port_or_known_default() {
+                attributes.push(int_attribute("server.port", port as i64));
+            }
+        }
+    }
+    if let Some(ip) = &request.ip {
+        attributes.push(attribute("client.address", ip));
+    }
+    if let Some(user_agent) = &request.user_agent {
+        attributes.push(attribute("user_agent.original", user_agent));
+    }
+    if let Some(size) = request
+        .headers
+        .get("content-length")
+        .and_then(|size| size.parse::<i64>().ok())
+    {
+        attributes.push(int_attribute("http.request.body.size", size));
+    }
+    if let Some(code) = response.code {
+        attributes.push(int_attribute("http.response.status_code", code as i64));
+        if code >= 500 {
+            attributes.push(attribute("error.type", &code.to_string()));
+        }
+    }
+    if let Some(size) = response.size {
+        attributes.push(int_attribute("http.response.body.size", size as i64));
+    }
+    if let Some(sample_rate) = payload.sample_rate {
+        attributes
+            .push(json!({ "key": "treblle.sample_rate", "value": { "doubleValue": sample_rate } }));
+    }
+    if export_headers {
+        for (prefix, headers) in [
+            ("http.request.header", &request.headers),
+            ("http.response.header", &response.headers),
+        ] {
+            let mut headers = headers.iter().collect::<Vec<_>>();
+            headers.sort();
+            for (name, value) in headers {
+                attributes.push(json!({
+                    "key": format!("{}.{}", prefix, name.to_lowercase()),
+                    "value": { "arrayValue": { "values": [{ "stringValue": value }] } },
+                }));
+            }
+        }
+    }
+
+    let events = payload
+        .data
+        .errors
+        .iter()
+        .map(|error| {
+            let mut attributes = vec![];
+            if let Some(r#type) = error["type"].as_str() {
+                attributes.push(attribute("exception.type", r#type));
+            }
+            let message = match error["message"].as_str() {
+                Some(message) => message.to_string(),
+                None => error.to_string(),
+            };
+            attributes.push(attribute("exception.message", &message));
+
+            json!({
+                "timeUnixNano": (start + duration).to_string(),
+                "name": "exception",
+                "attributes": attributes,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut rng = rand::thread_rng();
+    let parent = request
+        .headers
+        .get("traceparent")
+        .and_then(|traceparent| parse_traceparent(traceparent));
+    let (trace_id, parent_span_id) = match parent {
+        Some((trace_id, span_id)) => (trace_id, span_id),
+        None => (format!("{:032x}", rng.gen::<u128>()), String::new()),
+    };
+
+    let error = response.code.map(|code| code >= 500).unwrap_or(false);
+    json!({
+        "traceId": trace_id,
+        "spanId": format!("{:016x}", rng.gen::<u64>()),
+        "parentSpanId": parent_span_id,
+        "name": method,
+        "kind": 2,
+        "startTimeUnixNano": start.to_string(),
+        "endTimeUnixNano": (start + duration).to_string(),
+        "attributes": attributes,
+        "events": events,
+        "status": { "code": if error { 2 } else { 0 } },
+    })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// Integers are encoded as strings in OTLP JSON
+fn int_attribute(key: &str, value: i64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+/// Get the trace and the parent span id out of the W3C `traceparent` header
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let parts = value.trim().split('-').collect::<Vec<_>>();
+    match parts[..] {
+        [_, trace_id, span_id, _]
+            if trace_id.len() == 32
+                && span_id.len() == 16
+                && trace_id.chars().all(|c| c.is_ascii_hexdigit())
+                && span_id.chars().all(|c| c.is_ascii_hexdigit())
+                && trace_id.chars().any(|c| c != '0')
+                && span_id.chars().any(|c| c != '0') =>
+        {
+            Some((trace_id.to_lowercase(), span_id.to_lowercase()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OtlpTransport;
+    use crate::payload::TreblleData;
+    use crate::transport::test::endpoint;
+    use crate::transport::Transport;
+
+    #[actix_rt::test]
+    async fn exports_server_span_to_the_collector() {
+        let (endpoint, collector) = endpoint(1);
+
+        let mut payload = TreblleData::new("api_key".to_string(), "project_id".to_string());
+        let request = &mut payload.data.request;
+        request.method = Some("GET".to_string());
+        request.url = Some("http://localhost:8080/users?page=2".to_string());
+        request.headers.insert(
+            "traceparent".to_string(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string(),
+        );
+        payload.data.response.code = Some(503);
+
+        OtlpTransport::new(endpoint)
+            .unwrap()
+            .service_name("users-api")
+            .send(&payload)
+            .await
+            .unwrap();
+
+        let export = collector.join().unwrap().remove(0);
+        assert_eq!(export.path, "/v1/traces");
+
+        let resource = &export.body["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            "users-api"
+        );
+        let span = &resource["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["status"]["code"], 2);
+
+        let attribute = |key: &str| {
+            span["attributes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|attribute| attribute["key"] == key)
+                .map(|attribute| attribute["value"].clone())
+                .unwrap()
+        };
+        assert_eq!(attribute("http.request.method")["stringValue"], "GET");
+        assert_eq!(attribute("url.path")["stringValue"], "/users");
+        assert_eq!(attribute("url.query")["stringValue"], "page=2");
+        assert_eq!(attribute("http.response.status_code")["intValue"], "503");
+        assert_eq!(attribute("error.type")["stringValue"], "503");
+    }
+}
@@ -1,13 +1,15 @@
 use actix_web::dev::ServiceResponse;
 use chrono::{DateTime, Local, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 use std::collections::HashMap;
 
-use crate::extractors::Extractor;
+use crate::error::TreblleError;
+use crate::extractors::{parse_response_body, Extractor};
 
-#[derive(Serialize, Debug, Default)]
-pub(crate) struct TreblleResponseData {
+/// Response part of the payload
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TreblleResponseData {
     pub headers: HashMap<String, String>,
     pub code: Option<u16>,
     pub size: Option<u64>,
@@ -15,8 +17,9 @@ pub(crate) struct TreblleResponseData {
     pub body: Option<serde_json::Value>,
 }
 
-#[derive(Serialize, Debug, Default)]
-pub(crate) struct TreblleRequestData {
+/// Request part of the payload
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TreblleRequestData {
     pub timestamp: Option<String>,
     pub ip: Option<String>,
     pub url: Option<String>,
@@ -26,8 +29,9 @@ pub(crate) struct TreblleRequestData {
     pub body: Option<serde_json::Value>,
 }
 
-#[derive(Serialize, Debug)]
-pub(crate) struct TreblleLanguageData {
+/// Language the server is running on
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreblleLanguageData {
     pub name: String,
     pub version: String,
 }
@@ -41,8 +45,9 @@ impl Default for TreblleLanguageData {
     }
 }
 
-#[derive(Serialize, Debug)]
-pub(crate) struct TreblleServerOsData {
+/// Operating system the server is running on
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreblleServerOsData {
     pub name: String,
     pub release: String,
     pub architecture: String,
@@ -58,8 +63,9 @@ impl Default for TreblleServerOsData {
     }
 }
 
-#[derive(Serialize, Debug)]
-pub(crate) struct TreblleServerData {
+/// Server part of the payload
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreblleServerData {
     pub timezone: String,
     pub os: TreblleServerOsData,
     pub software: Option<String>,
@@ -79,8 +85,9 @@ impl Default for TreblleServerData {
     }
 }
 
-#[derive(Serialize, Debug, Default)]
-pub(crate) struct TreblleDataInner {
+/// Everything that was captured about the request and its response
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TreblleDataInner {
     pub server: TreblleServerData,
     pub language: TreblleLanguageData,
     pub request: TreblleRequestData,
@@ -88,37 +95,44 @@ pub(crate) struct TreblleDataInner {
     pub errors: Vec<serde_json::Value>,
 }
 
-#[derive(Serialize, Debug)]
-pub(crate) struct TreblleData {
-    #[serde(skip_serializing)]
+/// Payload that is delivered to Treblle for every logged request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreblleData {
+    #[serde(skip, default = "Utc::now")]
     pub start: DateTime<Utc>,
     pub api_key: String,
     pub project_id: String,
     pub version: String,
     pub sdk: String,
+    /// Chance the request had to be captured, set only when sampling is turned on so
+    /// the counts can be weighted back up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<f64>,
     pub data: TreblleDataInner,
 }
 
 impl TreblleData {
     /// Start the timer and create a new data instance
-    pub fn new(api_key: String, project_id: String) -> TreblleData {
+    pub(crate) fn new(api_key: String, project_id: String) -> TreblleData {
         Self {
             start: Utc::now(),
             api_key,
             project_id,
             sdk: "rust".to_string(),
             version: env!("CARGO_PKG_VERSION").to_string(),
+            sample_rate: None,
             data: TreblleDataInner::default(),
         }
     }
 
     /// Insert the request body before the execution of it starts
-    pub fn add_request_body(&mut self, body: serde_json::Value) {
+    pub(crate) fn add_request_body(&mut self, body: serde_json::Value) {
         self.data.request.body = Some(body);
     }
 
-    /// Collect the data from the service response and return it back
-    pub fn collect_data(mut self, sr: ServiceResponse) -> (ServiceResponse, TreblleData) {
+    /// Collect the data from the service response and return it back, the response body
+    /// is left out when it streams
+    pub(crate) fn collect_data(mut self, sr: ServiceResponse) -> (ServiceResponse, TreblleData) {
         let extractor = Extractor::new(sr);
 
         self.data.server.protocol = Some(extractor.get_protocol());
@@ -136,16 +150,33 @@ impl TreblleData {
         self.data.errors = extractor.get_errors();
 
         let (sr, body) = extractor.get_response_body();
-        self.data.response.body = Some(body);
+        self.data.response.body = body;
 
         self.data.response.load_time = Some(get_seconds_with_micro(self.start, None));
 
         (sr, self)
     }
 
+    /// Insert the response body once it was sent, bodies longer than what was captured
+    /// are kept as text cut off at the limit, bodies that weren't copied are Null
+    pub(crate) fn add_response_body(&mut self, body: Option<&[u8]>, size: u64) {
+        self.data.response.body = Some(match body {
+            Some(body) if size > body.len() as u64 => {
+                Value::String(String::from_utf8_lossy(body).into_owned())
+            }
+            Some(body) => parse_response_body(body),
+            None => Value::Null,
+        });
+        self.data.response.size = Some(size);
+        self.data.response.load_time = Some(get_seconds_with_micro(self.start, None));
+    }
+
     /// Run through request and response and mask all the fields
     /// String fields will be converted into '*', any other will be simply deleted.
-    pub fn mask_fields(&mut self, fields: Vec<String>) {
+    ///
+    /// Bodies that couldn't be parsed as JSON are masked completely if they mention any
+    /// of the fields, in which case an error with those fields is returned.
+    pub(crate) fn mask_fields(&mut self, fields: Vec<String>) -> Result<(), TreblleError> {
         let body = self.data.request.body.clone();
         self.data.request.body = body.map(|mut value| {
             clear_value(&mut value, &fields);
@@ -162,43 +193,72 @@ impl TreblleData {
 
         clear_hashmap(&mut self.data.request.headers, &fields);
         clear_hashmap(&mut self.data.response.headers, &fields);
+
+        let mut leaked = vec![];
+        let request_raw = self
+            .data
+            .request
+            .body
+            .as_mut()
+            .and_then(|body| body.get_mut("request_as_a_string"));
+        leaked.append(&mut clear_raw(request_raw, &fields));
+        leaked.append(&mut clear_raw(self.data.response.body.as_mut(), &fields));
+
+        if leaked.is_empty() {
+            Ok(())
+        } else {
+            leaked.sort();
+            leaked.dedup();
+
+            Err(TreblleError::Masking { fields: leaked })
+        }
     }
+}
 
-    /// Send where we don't wait for the execution of the request to finish
-    pub fn send(self) {
-        tokio::spawn(async move {
-            let client = reqwest::Client::new();
-            let _ = client
-                .post("https://rocknrolla.treblle.com")
-                .timeout(std::time::Duration::from_secs(2))
-                .header("x-api-key", &self.api_key)
-                .json(&self)
-                .send()
-                .await;
-        });
+/// Mask the whole body that couldn't be parsed if it mentions any of the fields as a
+/// key, returns the fields it mentions
+fn clear_raw(value: Option<&mut Value>, fields: &[String]) -> Vec<String> {
+    let raw = match value {
+        Some(Value::String(raw)) => raw,
+        _ => return vec![],
+    };
+
+    let leaked = fields
+        .iter()
+        .filter(|field| mentions_key(raw, field))
+        .cloned()
+        .collect::<Vec<String>>();
+
+    if !leaked.is_empty() {
+        *raw = "******".to_string();
     }
 
-    /// Send payload to Treblle
-    pub async fn send_debug(self) {
-        let client = reqwest::Client::new();
-        let req = client
-            .post("https://rocknrolla.treblle.com")
-            .timeout(std::time::Duration::from_secs(2))
-            .header("x-api-key", &self.api_key)
-            .json(&self)
-            .send()
-            .await;
-
-        match req {
-            Ok(res) => {
-                log::debug!("Response: {:#?}", res);
-
-                let body = res.text().await.unwrap_or_else(|_| "".to_string());
-                log::debug!("Response body: {}", body);
-            }
-            Err(e) => panic!("{:#?}", e),
-        };
+    leaked
+}
+
+/// Check if the raw body has the field as a whole key, either a JSON key like
+/// `"field":` or a form key like `field=` at the start of a pair, so words that only
+/// contain the field, like `acc=` for `cc`, don't count
+fn mentions_key(raw: &str, field: &str) -> bool {
+    if field.is_empty() {
+        return false;
     }
+
+    let json = format!("\"{}\"", field);
+    let json_key = raw
+        .match_indices(&json)
+        .any(|(i, key)| raw[i + key.len()..].trim_start().starts_with(':'));
+
+    let form = format!("{}=", field);
+    let form_key = raw.match_indices(&form).any(|(i, _)| {
+        raw[..i]
+            .chars()
+            .next_back()
+            .map(|c| matches!(c, '&' | '?' | ';') || c.is_whitespace())
+            .unwrap_or(true)
+    });
+
+    json_key || form_key
 }
 
 /// Replace given fields in the value with "*" or Null
@@ -301,6 +361,39 @@ mod test {
         assert!(item.child.ccv.is_none());
     }
 
+    #[test]
+    fn mask_unparsed_body_that_mentions_fields() {
+        let mut data = super::TreblleData::new("api_key".to_string(), "project_id".to_string());
+        data.data.response.body = Some(serde_json::Value::String(
+            "{\"password\": \"secret\",".to_string(),
+        ));
+
+        let err = data.mask_fields(vec!["password".to_string()]).unwrap_err();
+
+        assert!(matches!(
+            err,
+            crate::TreblleError::Masking { fields } if fields == vec!["password".to_string()]
+        ));
+        assert_eq!(data.data.response.body.unwrap(), "******");
+    }
+
+    #[test]
+    fn mask_unparsed_body_only_for_whole_keys() {
+        let fields = vec!["cc".to_string(), "pwd".to_string()];
+        let masked = |body: &str| {
+            let mut data = super::TreblleData::new("api_key".to_string(), "project_id".to_string());
+            data.data.response.body = Some(serde_json::Value::String(body.to_string()));
+
+            data.mask_fields(fields.clone()).is_err()
+        };
+
+        assert!(!masked("<a href=\"/login?acc=1\">the \"cc\" field</a>"));
+        assert!(!masked("tmp_pwd=1"));
+        assert!(masked("user=me&pwd=secret"));
+        assert!(masked("cc=4111"));
+        assert!(masked("{\"cc\" : \"4111\""));
+    }
+
     #[test]
     fn get_microseconds_duration() {
         let start = chrono::Utc::now();
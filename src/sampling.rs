@@ -0,0 +1,338 @@
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{HttpMessage, HttpRequest};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Responses that are always captured, no matter if the request was sampled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+    /// Responses with 5xx status code
+    ServerErrors,
+    /// Responses with 4xx status code
+    ClientErrors,
+    /// Responses that carry an error returned by a handler or a middleware
+    Errors,
+}
+
+/// Attribute of the request that identifies the client for consistent sampling
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientKey {
+    /// Value of the header, for example an API key
+    Header(String),
+    /// Value of the cookie, for example a session id
+    Cookie(String),
+    /// Resolved IP address of the client, same as the one in the payload
+    Ip,
+    /// [`SamplingKey`] that a middleware or the handler puts in the request extensions
+    Extension,
+}
+
+/// Value that identifies the client when sampling by [`ClientKey::Extension`]
+///
+/// When it's inserted before the request gets to Treblle, for example by an
+/// authentication middleware, the decision is made before the body is read. Otherwise
+/// the request body is captured and the decision is made once the handler is done.
+///
+/// ```rust,ignore
+/// async fn hello(req: HttpRequest) -> &'static str {
+///     req.extensions_mut().insert(actix_treblle::SamplingKey("user-42".to_string()));
+///     "Hello World!"
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SamplingKey(pub String);
+
+/// Decides which requests get captured
+///
+/// The rate of the matching route, or the global rate if there is none, is the chance
+/// between `0.0` and `1.0` that a request is captured. The decision is made before the
+/// request body is read, so requests that aren't sampled cost almost nothing.
+///
+/// Requests that aren't sampled can still be captured because of the [`Keep`] rules, but
+/// in that case their request body is not captured.
+///
+/// With a [`ClientKey`] the requests of the same client are sampled in or out together,
+/// the key is hashed and clients whose hash falls under the rate are captured. Requests
+/// without the key are sampled at random.
+///
+/// With a throughput target the rates are scaled down further whenever the traffic would
+/// produce more payloads than the target. Every payload records the rate it was sampled
+/// with, responses matching the [`Keep`] rules are recorded with the rate of `1.0`.
+///
+/// ```rust,ignore
+/// let sampling = actix_treblle::Sampling::new(0.1)
+///     .route("/health", 0.0)
+///     .route("/users/{user_id}", 0.5)
+///     .keep(actix_treblle::Keep::ServerErrors)
+///     .keep(actix_treblle::Keep::Errors)
+///     .max_per_second(50)
+///     .client_key(actix_treblle::ClientKey::Header("x-api-key".to_string()));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sampling {
+    rate: f64,
+    routes: HashMap<String, f64>,
+    keep: Vec<Keep>,
+    target: Option<(u32, Duration)>,
+    client_key: Option<ClientKey>,
+}
+
+impl Sampling {
+    /// Capture the given share of all requests
+    pub fn new(rate: f64) -> Sampling {
+        Sampling {
+            rate: rate.clamp(0.0, 1.0),
+            routes: HashMap::new(),
+            keep: vec![],
+            target: None,
+            client_key: None,
+        }
+    }
+
+    /// Use a different rate for the route matching pattern, same as you would define
+    /// it in your application
+    pub fn route<P: Into<String>>(mut self, pattern: P, rate: f64) -> Sampling {
+        self.routes.insert(pattern.into(), rate.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Always capture the responses matching the rule
+    pub fn keep(mut self, keep: Keep) -> Sampling {
+        if !self.keep.contains(&keep) {
+            self.keep.push(keep);
+        }
+        self
+    }
+
+    /// Adjust the rates every second so that at most the given number of payloads per
+    /// second is captured
+    pub fn max_per_second(mut self, payloads: u32) -> Sampling {
+        self.target = Some((payloads, Duration::from_secs(1)));
+        self
+    }
+
+    /// Adjust the rates every minute so that at most the given number of payloads per
+    /// minute is captured
+    pub fn max_per_minute(mut self, payloads: u32) -> Sampling {
+        self.target = Some((payloads, Duration::from_secs(60)));
+        self
+    }
+
+    /// Sample all the requests of a client in or out together
+    pub fn client_key(mut self, key: ClientKey) -> Sampling {
+        self.client_key = Some(key);
+        self
+    }
+}
+
+/// Outcome of the sampling before the request is handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Decision {
+    /// Request is captured, with the chance it had to be sampled
+    Sampled(f64),
+    /// Client key isn't known yet, request is captured and the decision is made with
+    /// the rate once the handler is done
+    Undecided(f64),
+    /// Request isn't sampled, but the response might still be kept
+    Deferred,
+    /// Request isn't captured at all
+    Skipped,
+}
+
+/// Traffic seen during the current adjustment period
+struct Window {
+    started: Instant,
+    /// Payloads that would be captured without the throughput target
+    expected: f64,
+    /// Scale applied to the rates during the period
+    scale: f64,
+}
+
+pub(crate) struct Sampler {
+    config: Sampling,
+    window: Mutex<Window>,
+}
+
+impl Sampler {
+    pub fn new(config: Sampling) -> Sampler {
+        Sampler {
+            config,
+            window: Mutex::new(Window {
+                started: Instant::now(),
+                expected: 0.0,
+                scale: 1.0,
+            }),
+        }
+    }
+
+    /// Decide on the request before its body is read
+    pub fn decide(&self, req: &ServiceRequest) -> Decision {
+        let rate = req
+            .match_pattern()
+            .and_then(|pattern| self.config.routes.get(&pattern).copied())
+            .unwrap_or(self.config.rate);
+        let rate = self.adapt(rate);
+
+        let key = self.client_key(req.request());
+        if key.is_none() && self.config.client_key == Some(ClientKey::Extension) {
+            return Decision::Undecided(rate);
+        }
+
+        self.draw(rate, key.as_deref())
+    }
+
+    /// Make the decision that was left until the handler was done
+    pub fn decide_late(&self, req: &HttpRequest, rate: f64) -> Decision {
+        self.draw(rate, self.client_key(req).as_deref())
+    }
+
+    fn draw(&self, rate: f64, key: Option<&str>) -> Decision {
+        let draw = match key {
+            Some(key) => hash_to_unit(key),
+            None => rand::thread_rng().gen::<f64>(),
+        };
+
+        if rate >= 1.0 || (rate > 0.0 && draw < rate) {
+            Decision::Sampled(rate)
+        } else if self.config.keep.is_empty() {
+            Decision::Skipped
+        } else {
+            Decision::Deferred
+        }
+    }
+
+    /// Value of the client key, if there is one configured and the request has it
+    fn client_key(&self, req: &HttpRequest) -> Option<String> {
+        match self.config.client_key.as_ref()? {
+            ClientKey::Header(name) => req
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+            ClientKey::Cookie(name) => req
+                .headers()
+                .get_all(header::COOKIE)
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.to_string()),
+            ClientKey::Ip => req
+                .connection_info()
+                .realip_remote_addr()
+                .map(|ip| ip.to_string()),
+            ClientKey::Extension => req
+                .extensions()
+                .get::<SamplingKey>()
+                .map(|key| key.0.clone()),
+        }
+    }
+
+    /// Scale the rate down to stay under the throughput target, the scale is set for
+    /// every period based on the traffic seen during the previous one
+    fn adapt(&self, rate: f64) -> f64 {
+        let (payloads, period) = match self.config.target {
+            Some(target) => target,
+            None => return rate,
+        };
+
+        let mut window = self.window.lock().unwrap();
+        let elapsed = window.started.elapsed();
+        if elapsed >= period {
+            let target = payloads as f64 * elapsed.as_secs_f64() / period.as_secs_f64();
+            window.scale = if window.expected > target {
+                target / window.expected
+            } else {
+                1.0
+            };
+            window.started = Instant::now();
+            window.expected = 0.0;
+        }
+        window.expected += rate;
+
+        rate * window.scale
+    }
+
+    /// Check if the response of a request that wasn't sampled should be kept anyway
+    pub fn keep(&self, res: &ServiceResponse) -> bool {
+        let status = res.status();
+
+        self.config.keep.iter().any(|keep| match keep {
+            Keep::ServerErrors => status.is_server_error(),
+            Keep::ClientErrors => status.is_client_error(),
+            Keep::Errors => res.response().error().is_some(),
+        })
+    }
+}
+
+/// Map the key to a number between `0.0` and `1.0` with FNV-1a, so the same client gets
+/// the same number in every process and on every machine
+fn hash_to_unit(key: &str) -> f64 {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientKey, Decision, Sampler, Sampling, SamplingKey};
+    use actix_web::test::TestRequest;
+    use actix_web::HttpMessage;
+    use std::time::Duration;
+
+    #[test]
+    fn scales_rate_down_to_the_target() {
+        let sampler = Sampler::new(Sampling::new(1.0).max_per_second(10));
+
+        for _ in 0..100 {
+            assert_eq!(sampler.adapt(1.0), 1.0);
+        }
+
+        // Pretend that the period has passed
+        sampler.window.lock().unwrap().started -= Duration::from_secs(1);
+        let rate = sampler.adapt(1.0);
+        assert!(rate > 0.09 && rate < 0.11, "rate was {}", rate);
+        assert!((sampler.adapt(0.5) - rate / 2.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn samples_clients_consistently() {
+        let sampler =
+            Sampler::new(Sampling::new(0.5).client_key(ClientKey::Cookie("session".to_string())));
+        let decide = |session: &str| {
+            let req = TestRequest::default()
+                .insert_header(("cookie", format!("theme=dark; session={}", session)))
+                .to_srv_request();
+            sampler.decide(&req)
+        };
+
+        let decisions = (0..20)
+            .map(|client| decide(&client.to_string()))
+            .collect::<Vec<_>>();
+        for _ in 0..10 {
+            for (client, decision) in decisions.iter().enumerate() {
+                assert_eq!(decide(&client.to_string()), *decision);
+            }
+        }
+        assert!(decisions.contains(&Decision::Sampled(0.5)));
+        assert!(decisions.contains(&Decision::Skipped));
+    }
+
+    #[test]
+    fn waits_for_the_extension_key() {
+        let sampler = Sampler::new(Sampling::new(0.5).client_key(ClientKey::Extension));
+        let req = TestRequest::default().to_srv_request();
+        assert_eq!(sampler.decide(&req), Decision::Undecided(0.5));
+
+        req.extensions_mut()
+            .insert(SamplingKey("user-42".to_string()));
+        let late = sampler.decide_late(req.request(), 0.5);
+        assert_ne!(late, Decision::Undecided(0.5));
+        assert_eq!(sampler.decide(&req), late);
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::{Arc, OnceLock};
+
+use crate::breaker::CircuitBreaker;
+use crate::delivery::{Batching, DeliveryConfig, DeliveryStats, Dispatcher, DropPolicy, Retry};
+use crate::error::ErrorHandler;
+use crate::sampling::{Sampler, Sampling};
+use crate::spool::Spool;
+use crate::transport::Transport;
+use crate::treblle::default_masking_fields;
+
+/// Additional destination for the captured payloads, next to Treblle.com
+///
+/// Every sink has its own masking, sampling and delivery queue with its own worker, so
+/// a sink that is slow or failing doesn't hold back the others. Masking starts with the
+/// same default fields as [`crate::Treblle`].
+///
+/// ```rust,ignore
+/// let audit = actix_treblle::Sink::new("audit", MyTransport::default())
+///     .add_masking_fields(vec!["email".to_string()])
+///     .sampling(actix_treblle::Sampling::new(1.0))
+///     .queue_capacity(10_000);
+///
+/// HttpServer::new(move || {
+///     App::new()
+///         .wrap(
+///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+///                .sink(audit.clone())
+///         )
+///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+/// })
+/// .bind(("127.0.0.1", 8080))?
+/// .run()
+/// .await
+/// ```
+#[derive(Clone)]
+pub struct Sink {
+    pub(crate) name: String,
+    pub(crate) transport: Arc<dyn Transport>,
+    pub(crate) masking_fields: Vec<String>,
+    pub(crate) sampler: Option<Arc<Sampler>>,
+    pub(crate) delivery: DeliveryConfig,
+    pub(crate) dispatcher: Arc<OnceLock<Arc<Dispatcher>>>,
+}
+
+impl Sink {
+    /// Create a sink that delivers payloads with the given transport, the name is used
+    /// to tell the sinks apart in the logs
+    pub fn new<N: Into<String>, T: Transport + 'static>(name: N, transport: T) -> Sink {
+        Sink {
+            name: name.into(),
+            transport: Arc::new(transport),
+            masking_fields: default_masking_fields(),
+            sampler: None,
+            delivery: DeliveryConfig::default(),
+            dispatcher: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// Name of the sink
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Remove the masking fields, including the default ones
+    pub fn clear_masking_fields(mut self) -> Sink {
+        self.masking_fields.clear();
+        self
+    }
+
+    /// Mask more fields before the payload is given to this sink
+    pub fn add_masking_fields(mut self, mut fields: Vec<String>) -> Sink {
+        self.masking_fields.append(&mut fields);
+        self
+    }
+
+    /// Capture only a share of the requests for this sink, see [`Sampling`]
+    pub fn sampling(mut self, sampling: Sampling) -> Sink {
+        self.sampler = Some(Arc::new(Sampler::new(sampling)));
+        self
+    }
+
+    /// Maximum number of payloads waiting in the queue of this sink
+    pub fn queue_capacity(mut self, capacity: usize) -> Sink {
+        self.delivery.capacity = capacity;
+        self
+    }
+
+    /// What happens with new payloads when the queue of this sink is full
+    pub fn drop_policy(mut self, policy: DropPolicy) -> Sink {
+        self.delivery.policy = policy;
+        self
+    }
+
+    /// Group payloads into a single delivery, see [`Batching`]
+    pub fn batching(mut self, batching: Batching) -> Sink {
+        self.delivery.batching = Some(batching);
+        self
+    }
+
+    /// Retry the failed deliveries, see [`Retry`]
+    pub fn retry(mut self, retry: Retry) -> Sink {
+        self.delivery.retry = Some(retry);
+        self
+    }
+
+    /// Write payloads to disk when the sink is unreachable, see [`Spool`]. Every sink
+    /// needs a directory of its own.
+    pub fn spool(mut self, spool: Spool) -> Sink {
+        self.delivery.spool = Some(spool);
+        self
+    }
+
+    /// Stop delivering to the sink for a while when it keeps failing, see [`CircuitBreaker`]
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Sink {
+        self.delivery.breaker = Some(breaker);
+        self
+    }
+
+    /// Counters of queued, dropped, delivered and failed payloads of this sink
+    pub fn delivery_stats(&self) -> DeliveryStats {
+        self.dispatcher
+            .get()
+            .map(|dispatcher| dispatcher.stats())
+            .unwrap_or_default()
+    }
+
+    /// Dispatcher shared by all the clones, the worker is started on first use
+    pub(crate) fn dispatcher(&self, on_error: Option<ErrorHandler>) -> Arc<Dispatcher> {
+        self.dispatcher
+            .get_or_init(|| {
+                Arc::new(Dispatcher::start(
+                    self.transport.clone(),
+                    self.delivery.clone(),
+                    on_error,
+                ))
+            })
+            .clone()
+    }
+}
+
+/// Everything the middleware needs to deliver a payload to one destination
+pub(crate) struct Destination {
+    pub masking_fields: Vec<String>,
+    pub sampler: Option<Arc<Sampler>>,
+    pub dispatcher: Arc<Dispatcher>,
+}
@@ -0,0 +1,364 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::payload::TreblleData;
+
+/// Disk backed storage for payloads that couldn't be delivered because the endpoint
+/// was unreachable. Payloads are appended to segment files inside of the given
+/// directory and sent again once the endpoint recovers, even after a restart.
+///
+/// ```rust,ignore
+/// let spool = actix_treblle::Spool::new("/var/spool/treblle")
+///     .max_bytes(256 * 1024 * 1024)
+///     .segment_bytes(16 * 1024 * 1024);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spool {
+    pub(crate) dir: PathBuf,
+    pub(crate) max_bytes: u64,
+    pub(crate) segment_bytes: u64,
+}
+
+impl Spool {
+    /// Spool payloads into the given directory, it will be created if it doesn't exist.
+    /// By default the spool takes at most 100MB split in segments of 8MB.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Spool {
+        Spool {
+            dir: dir.into(),
+            max_bytes: 100 * 1024 * 1024,
+            segment_bytes: 8 * 1024 * 1024,
+        }
+    }
+
+    /// Maximum size of all the segments together, oldest segments are deleted to make
+    /// room for the new payloads
+    pub fn max_bytes(mut self, max_bytes: u64) -> Spool {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Size after which a new segment file is started
+    pub fn segment_bytes(mut self, segment_bytes: u64) -> Spool {
+        self.segment_bytes = segment_bytes;
+        self
+    }
+}
+
+/// Segment that lives on the disk
+struct Segment {
+    seq: u64,
+    bytes: u64,
+}
+
+/// Payloads that didn't end up in the spool after an append
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Appended {
+    /// Payloads of the old segments that were deleted to stay within the size limit
+    pub lost: u64,
+    /// Appended payloads that didn't fit into the spool and were not written
+    pub rejected: u64,
+}
+
+/// Append only segment files with one JSON payload per line
+pub(crate) struct SpoolStore {
+    config: Spool,
+    /// Segments on the disk, oldest first
+    segments: VecDeque<Segment>,
+    /// Last segment is open for writing until it's full
+    active: Option<File>,
+    /// Exclusive lock of the directory, held as long as the store is open
+    _lock: File,
+}
+
+impl SpoolStore {
+    /// Open the spool directory and pick up the segments left by the previous process.
+    ///
+    /// Directory can be used by a single spool at a time, since every spool numbers and
+    /// deletes the segments on its own, opening a directory that is already in use fails.
+    pub fn open(config: Spool) -> io::Result<SpoolStore> {
+        fs::create_dir_all(&config.dir)?;
+
+        let lock = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(config.dir.join("spool.lock"))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    format!(
+                        "{} is already used by another spool, share one Treblle between \
+                         the workers or give every spool its own directory",
+                        config.dir.display()
+                    ),
+                ))
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+
+        let mut segments = vec![];
+        for entry in fs::read_dir(&config.dir)? {
+            let entry = entry?;
+            let seq = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".spool"))
+                .and_then(|seq| seq.parse::<u64>().ok());
+
+            if let Some(seq) = seq {
+                segments.push(Segment {
+                    seq,
+                    bytes: entry.metadata()?.len(),
+                });
+            }
+        }
+        segments.sort_by_key(|segment| segment.seq);
+
+        Ok(SpoolStore {
+            config,
+            segments: segments.into(),
+            active: None,
+            _lock: lock,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Append the payloads to the active segment, returns how many payloads were lost
+    /// because old segments had to be deleted to stay within the size limit, and how
+    /// many of the given payloads didn't fit at all.
+    pub fn append(&mut self, payloads: &[TreblleData]) -> io::Result<Appended> {
+        let mut lines = vec![];
+        for payload in payloads {
+            serde_json::to_writer(&mut lines, payload)?;
+            lines.push(b'\n');
+        }
+        let len = lines.len() as u64;
+
+        let mut appended = Appended::default();
+        // Nothing is deleted for payloads that wouldn't fit even into an empty spool
+        if len > self.config.max_bytes {
+            appended.rejected = payloads.len() as u64;
+            return Ok(appended);
+        }
+
+        while self.total_bytes() + len > self.config.max_bytes && self.segments.len() > 1 {
+            appended.lost += self.drop_oldest()?;
+        }
+        if self.total_bytes() + len > self.config.max_bytes {
+            appended.rejected = payloads.len() as u64;
+            return Ok(appended);
+        }
+
+        let full = self
+            .segments
+            .back()
+            .map(|segment| segment.bytes >= self.config.segment_bytes)
+            .unwrap_or(true);
+        if self.active.is_none() || full {
+            self.rotate()?;
+        }
+
+        if let (Some(file), Some(segment)) = (self.active.as_mut(), self.segments.back_mut()) {
+            file.write_all(&lines)?;
+            file.flush()?;
+            segment.bytes += len;
+        }
+
+        Ok(appended)
+    }
+
+    /// Take the payloads of the oldest segment, active segment is sealed if it's the
+    /// only one left so new payloads don't end up in the segment that's being drained.
+    pub fn oldest(&mut self) -> io::Result<Option<(u64, Vec<TreblleData>)>> {
+        let seq = match self.segments.front() {
+            Some(segment) => segment.seq,
+            None => return Ok(None),
+        };
+        if self.segments.len() == 1 {
+            self.active = None;
+        }
+
+        Ok(Some((seq, read_segment(&self.path(seq))?)))
+    }
+
+    /// Delete the segment once all of its payloads were delivered
+    pub fn remove(&mut self, seq: u64) -> io::Result<()> {
+        self.segments.retain(|segment| segment.seq != seq);
+        remove_file(&self.path(seq))
+    }
+
+    /// Replace the segment content with the payloads that are still not delivered
+    pub fn rewrite(&mut self, seq: u64, payloads: &[TreblleData]) -> io::Result<()> {
+        let mut lines = vec![];
+        for payload in payloads {
+            serde_json::to_writer(&mut lines, payload)?;
+            lines.push(b'\n');
+        }
+
+        let tmp = self.config.dir.join(format!("{:020}.tmp", seq));
+        fs::write(&tmp, &lines)?;
+        fs::rename(&tmp, self.path(seq))?;
+
+        if let Some(segment) = self.segments.iter_mut().find(|s| s.seq == seq) {
+            segment.bytes = lines.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.bytes).sum()
+    }
+
+    /// Start a new segment
+    fn rotate(&mut self) -> io::Result<()> {
+        let seq = self.segments.back().map(|s| s.seq + 1).unwrap_or(0);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(seq))?;
+
+        self.segments.push_back(Segment { seq, bytes: 0 });
+        self.active = Some(file);
+
+        Ok(())
+    }
+
+    /// Delete the oldest segment and return the number of payloads that were in it
+    fn drop_oldest(&mut self) -> io::Result<u64> {
+        let segment = match self.segments.pop_front() {
+            Some(segment) => segment,
+            None => return Ok(0),
+        };
+        let path = self.path(segment.seq);
+        let count = BufReader::new(File::open(&path)?).lines().count() as u64;
+        remove_file(&path)?;
+
+        log::warn!(
+            "Treblle spool is full, dropped {} payloads from {}",
+            count,
+            path.display()
+        );
+
+        Ok(count)
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.config.dir.join(format!("{:020}.spool", seq))
+    }
+}
+
+/// Read the payloads from the segment, lines that can't be parsed (for example the last
+/// line that was cut off by a crash) are skipped.
+fn read_segment(path: &Path) -> io::Result<Vec<TreblleData>> {
+    let mut payloads = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        match serde_json::from_str::<TreblleData>(&line?) {
+            Ok(payload) => payloads.push(payload),
+            Err(e) => log::warn!("Skipping corrupted payload in {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(payloads)
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Appended, Spool, SpoolStore};
+    use crate::payload::TreblleData;
+
+    fn payload(project_id: &str) -> TreblleData {
+        TreblleData::new("api_key".to_string(), project_id.to_string())
+    }
+
+    #[test]
+    fn survives_reopening_and_rotates_segments() {
+        let dir = std::env::temp_dir().join(format!("treblle-spool-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = Spool::new(&dir).segment_bytes(1);
+
+        let mut spool = SpoolStore::open(config.clone()).unwrap();
+        spool.append(&[payload("first")]).unwrap();
+        spool.append(&[payload("second")]).unwrap();
+        drop(spool);
+
+        let mut spool = SpoolStore::open(config).unwrap();
+        let (seq, payloads) = spool.oldest().unwrap().unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].project_id, "first");
+        spool.remove(seq).unwrap();
+
+        let (seq, payloads) = spool.oldest().unwrap().unwrap();
+        assert_eq!(payloads[0].project_id, "second");
+        spool.remove(seq).unwrap();
+
+        assert!(spool.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn locks_the_directory_and_rejects_payloads_that_do_not_fit() {
+        let dir = std::env::temp_dir().join(format!("treblle-lock-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = Spool::new(&dir).max_bytes(16);
+
+        let mut spool = SpoolStore::open(config.clone()).unwrap();
+        let e = SpoolStore::open(config.clone()).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::ResourceBusy);
+
+        let appended = spool
+            .append(&[payload("first"), payload("second")])
+            .unwrap();
+        assert_eq!(
+            appended,
+            Appended {
+                lost: 0,
+                rejected: 2
+            }
+        );
+        assert!(spool.is_empty());
+
+        drop(spool);
+        assert!(SpoolStore::open(config).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeps_spooled_payloads_when_a_batch_is_too_big_for_the_spool() {
+        let dir = std::env::temp_dir().join(format!("treblle-oversized-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let line = serde_json::to_vec(&payload("first")).unwrap().len() as u64 + 1;
+        let config = Spool::new(&dir).max_bytes(3 * line).segment_bytes(1);
+
+        let mut spool = SpoolStore::open(config).unwrap();
+        spool.append(&[payload("first")]).unwrap();
+        spool.append(&[payload("other")]).unwrap();
+
+        let batch = ["a", "b", "c", "d"].map(payload);
+        assert_eq!(
+            spool.append(&batch).unwrap(),
+            Appended {
+                lost: 0,
+                rejected: 4
+            }
+        );
+
+        let (_, payloads) = spool.oldest().unwrap().unwrap();
+        assert_eq!(payloads[0].project_id, "first");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
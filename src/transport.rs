@@ -0,0 +1,597 @@
+use futures::future::BoxFuture;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::compression::Compression;
+use crate::payload::TreblleData;
+
+/// Delivery mechanism for captured payloads.
+///
+/// By default payloads are POSTed to Treblle.com with [`HttpTransport`], but you can
+/// implement this trait to deliver them anywhere else (in-memory for tests, a file,
+/// an internal relay...) and give it to [`crate::Treblle::transport`].
+///
+/// ```rust,ignore
+/// struct StdoutTransport;
+///
+/// impl actix_treblle::Transport for StdoutTransport {
+///     fn send<'a>(
+///         &'a self,
+///         payload: &'a actix_treblle::TreblleData,
+///     ) -> BoxFuture<'a, Result<(), actix_treblle::TransportError>> {
+///         Box::pin(async move {
+///             println!("{}", serde_json::to_string(payload)?);
+///             Ok(())
+///         })
+///     }
+/// }
+/// ```
+pub trait Transport: Send + Sync {
+    /// Deliver a single payload
+    fn send<'a>(&'a self, payload: &'a TreblleData) -> BoxFuture<'a, Result<(), TransportError>>;
+
+    /// Deliver multiple payloads at once when batching is turned on
+    ///
+    /// The error comes with the number of payloads at the start of the batch that were
+    /// delivered before the failure, only the rest of them is retried or spooled. By default
+    /// payloads are sent one by one until the first failure, override this if your
+    /// destination can accept all of them in a single upload.
+    fn send_batch<'a>(
+        &'a self,
+        payloads: &'a [TreblleData],
+    ) -> BoxFuture<'a, Result<(), (usize, TransportError)>> {
+        Box::pin(async move {
+            for (sent, payload) in payloads.iter().enumerate() {
+                self.send(payload).await.map_err(|e| (sent, e))?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Error returned by a [`Transport`] when the payload couldn't be delivered
+#[derive(Debug)]
+pub enum TransportError {
+    /// Payload couldn't be serialized
+    Serialization(serde_json::Error),
+    /// Request couldn't be made or the connection failed
+    Http(reqwest::Error),
+    /// Endpoint responded with a non successful status code
+    Status {
+        code: u16,
+        body: String,
+        retry_after: Option<Duration>,
+    },
+    /// Endpoint accepted the request, but reported errors about the payload
+    Rejected { code: u16, reasons: Vec<String> },
+    /// Reading or writing failed
+    Io(std::io::Error),
+    /// Any other failure of a custom transport
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Serialization(e) => write!(f, "payload serialization failed: {}", e),
+            TransportError::Http(e) => write!(f, "request failed: {}", e),
+            TransportError::Status { code, body, .. } => match rejection_reasons(body) {
+                reasons if reasons.is_empty() => {
+                    write!(f, "endpoint responded with {}: {}", code, body)
+                }
+                reasons => write!(
+                    f,
+                    "endpoint responded with {}: {}",
+                    code,
+                    reasons.join("; ")
+                ),
+            },
+            TransportError::Rejected { code, reasons } => {
+                write!(
+                    f,
+                    "endpoint rejected the payload with {}: {}",
+                    code,
+                    reasons.join("; ")
+                )
+            }
+            TransportError::Io(e) => write!(f, "io failure: {}", e),
+            TransportError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl TransportError {
+    /// Failures that might go away if the delivery is tried again: connection failures,
+    /// timeouts, 5xx responses and 429 Too Many Requests
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TransportError::Http(e) => e.is_connect() || e.is_timeout(),
+            TransportError::Status { code, .. } => *code >= 500 || *code == 429,
+            _ => false,
+        }
+    }
+
+    /// Reasons the endpoint gave for not accepting the payload
+    pub fn reasons(&self) -> Vec<String> {
+        match self {
+            TransportError::Status { body, .. } => rejection_reasons(body),
+            TransportError::Rejected { reasons, .. } => reasons.clone(),
+            _ => vec![],
+        }
+    }
+
+    /// How long the endpoint asked us to wait before trying again
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            TransportError::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransportError::Serialization(e) => Some(e),
+            TransportError::Http(e) => Some(e),
+            TransportError::Io(e) => Some(e),
+            TransportError::Other(e) => Some(e.as_ref()),
+            TransportError::Status { .. } | TransportError::Rejected { .. } => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for TransportError {
+    fn from(e: serde_json::Error) -> TransportError {
+        TransportError::Serialization(e)
+    }
+}
+
+impl From<reqwest::Error> for TransportError {
+    fn from(e: reqwest::Error) -> TransportError {
+        TransportError::Http(e)
+    }
+}
+
+impl From<std::io::Error> for TransportError {
+    fn from(e: std::io::Error) -> TransportError {
+        TransportError::Io(e)
+    }
+}
+
+/// Treblle's default ingestion endpoint
+pub const DEFAULT_ENDPOINT: &str = "https://rocknrolla.treblle.com";
+
+/// Named presets of Treblle ingestion endpoints
+///
+/// Only the global endpoints are known for now, point the middleware at any other
+/// ingestion endpoint Treblle gives you with [`crate::Treblle::endpoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Region {
+    /// Treblle's global ingestion endpoints, payloads are spread across all of them
+    Global,
+}
+
+impl Region {
+    /// Endpoints that belong to the region
+    pub fn endpoints(&self) -> Vec<String> {
+        match self {
+            Region::Global => vec![
+                "https://rocknrolla.treblle.com".to_string(),
+                "https://punisher.treblle.com".to_string(),
+                "https://sicario.treblle.com".to_string(),
+            ],
+        }
+    }
+}
+
+/// Settings of the HTTP client that [`HttpTransport`] creates
+///
+/// TLS backend is picked with the `native-tls` (default) or `rustls` cargo feature.
+///
+/// ```rust,ignore
+/// let config = actix_treblle::HttpClientConfig::new()
+///     .connect_timeout(std::time::Duration::from_millis(500))
+///     .timeout(std::time::Duration::from_secs(5))
+///     .proxy(reqwest::Proxy::https("http://egress.internal:3128")?)
+///     .add_root_certificate(reqwest::Certificate::from_pem(&ca)?);
+/// ```
+#[derive(Clone)]
+pub struct HttpClientConfig {
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    proxy: Option<reqwest::Proxy>,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    root_certificates: Vec<reqwest::Certificate>,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    identity: Option<reqwest::Identity>,
+}
+
+impl HttpClientConfig {
+    /// Default settings, with the total timeout of 2 seconds per request
+    pub fn new() -> HttpClientConfig {
+        HttpClientConfig {
+            connect_timeout: None,
+            timeout: Some(Duration::from_secs(2)),
+            proxy: None,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            root_certificates: vec![],
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            identity: None,
+        }
+    }
+
+    /// Timeout for establishing the connection
+    pub fn connect_timeout(mut self, timeout: Duration) -> HttpClientConfig {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Timeout for the whole request, from connecting until the response is read
+    pub fn timeout(mut self, timeout: Duration) -> HttpClientConfig {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send the requests through a HTTP(S) proxy
+    pub fn proxy(mut self, proxy: reqwest::Proxy) -> HttpClientConfig {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Trust an additional root certificate, for example the one of your egress relay
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub fn add_root_certificate(mut self, certificate: reqwest::Certificate) -> HttpClientConfig {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    /// Client certificate for mutual TLS
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub fn identity(mut self, identity: reqwest::Identity) -> HttpClientConfig {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Build the client with these settings
+    pub fn build(&self) -> reqwest::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+
+        #[cfg(feature = "rustls")]
+        {
+            builder = builder.use_rustls_tls();
+        }
+
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = self.proxy.clone() {
+            builder = builder.proxy(proxy);
+        }
+
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        {
+            for certificate in self.root_certificates.iter().cloned() {
+                builder = builder.add_root_certificate(certificate);
+            }
+            if let Some(identity) = self.identity.clone() {
+                builder = builder.identity(identity);
+            }
+        }
+
+        builder.build()
+    }
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> HttpClientConfig {
+        HttpClientConfig::new()
+    }
+}
+
+/// Default transport which POSTs payloads to Treblle.com
+pub struct HttpTransport {
+    client: reqwest::Client,
+    endpoints: Vec<String>,
+    next: AtomicUsize,
+    compression: Option<(Compression, usize)>,
+}
+
+impl HttpTransport {
+    /// Create the transport with its own HTTP client that is reused for every payload,
+    /// fails if the client can't be built, for example when the TLS backend can't be
+    /// initialized
+    pub fn new() -> reqwest::Result<HttpTransport> {
+        Ok(HttpTransport::with_client(
+            HttpClientConfig::default().build()?,
+        ))
+    }
+
+    /// Create the transport with a client you already have, so its connection pool can be
+    /// shared with the rest of your application. Timeouts of the client are used as they are.
+    ///
+    /// Connections opened while delivering stay bound to the runtime of the delivery
+    /// worker, see [`crate::Treblle::http_client`] for why the client shouldn't be used
+    /// after the shutdown.
+    pub fn with_client(client: reqwest::Client) -> HttpTransport {
+        HttpTransport {
+            client,
+            endpoints: vec![DEFAULT_ENDPOINT.to_string()],
+            next: AtomicUsize::new(0),
+            compression: None,
+        }
+    }
+
+    /// Compress request bodies that are at least `min_bytes` big with the given encoding
+    pub fn compression(mut self, compression: Compression, min_bytes: usize) -> HttpTransport {
+        self.compression = Some((compression, min_bytes));
+        self
+    }
+
+    /// Send payloads to the given endpoints instead of the default one, when more than
+    /// one endpoint is given they will be used in a round robin fashion.
+    ///
+    /// Passing an empty vector keeps the endpoints that were set before.
+    pub fn endpoints(mut self, endpoints: Vec<String>) -> HttpTransport {
+        if !endpoints.is_empty() {
+            self.endpoints = endpoints;
+        }
+        self
+    }
+
+    /// Pick the endpoint the next payload will be sent to
+    fn next_endpoint(&self) -> &str {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.endpoints.len();
+
+        &self.endpoints[i]
+    }
+}
+
+impl HttpTransport {
+    /// POST the JSON body to the next endpoint
+    async fn post<B: serde::Serialize + ?Sized>(
+        &self,
+        api_key: &str,
+        body: &B,
+    ) -> Result<(), TransportError> {
+        let mut body = serde_json::to_vec(body)?;
+        let mut req = self
+            .client
+            .post(self.next_endpoint())
+            .header("x-api-key", api_key)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+
+        if let Some((compression, min_bytes)) = self.compression {
+            if body.len() >= min_bytes {
+                body = compression.encode(&body)?;
+                req = req.header(
+                    reqwest::header::CONTENT_ENCODING,
+                    compression.content_encoding(),
+                );
+            }
+        }
+
+        let res = req.body(body).send().await?;
+
+        let code = res.status().as_u16();
+        if !res.status().is_success() {
+            let retry_after = res
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after);
+            let body = res.text().await.unwrap_or_default();
+
+            return Err(TransportError::Status {
+                code,
+                body,
+                retry_after,
+            });
+        }
+
+        // Successful status can still come with errors about the payload in the body
+        let body = res.text().await.unwrap_or_default();
+        let reasons = serde_json::from_str::<serde_json::Value>(&body)
+            .map(|body| collect_reasons(&body["errors"]))
+            .unwrap_or_default();
+        if !reasons.is_empty() {
+            return Err(TransportError::Rejected { code, reasons });
+        }
+
+        log::debug!("Treblle responded with {}: {}", code, body);
+
+        Ok(())
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send<'a>(&'a self, payload: &'a TreblleData) -> BoxFuture<'a, Result<(), TransportError>> {
+        Box::pin(self.post(&payload.api_key, payload))
+    }
+}
+
+/// Transport used when the HTTP client couldn't be configured, every delivery fails with
+/// the configuration error instead of going out without the proxy or certificates
+pub(crate) struct Misconfigured(pub String);
+
+impl Transport for Misconfigured {
+    fn send<'a>(&'a self, _payload: &'a TreblleData) -> BoxFuture<'a, Result<(), TransportError>> {
+        Box::pin(async move {
+            Err(TransportError::Other(
+                format!("HTTP client couldn't be configured: {}", self.0).into(),
+            ))
+        })
+    }
+}
+
+/// Parse the Retry-After header which is either a number of seconds or an HTTP date
+pub(crate) fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// Pull the reasons out of the error response, JSON responses are searched for
+/// `errors`, `error` and `message` fields, anything else is taken as it is.
+fn rejection_reasons(body: &str) -> Vec<String> {
+    let body = body.trim();
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(value) => ["errors", "error", "message"]
+            .iter()
+            .flat_map(|key| collect_reasons(&value[*key]))
+            .collect(),
+        Err(_) if !body.is_empty() => vec![body.to_string()],
+        Err(_) => vec![],
+    }
+}
+
+/// Collect all the strings from the value, no matter how deeply they are nested
+fn collect_reasons(value: &serde_json::Value) -> Vec<String> {
+    match value {
+        serde_json::Value::String(s) => vec![s.clone()],
+        serde_json::Value::Array(values) => values.iter().flat_map(collect_reasons).collect(),
+        serde_json::Value::Object(map) => map.values().flat_map(collect_reasons).collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{rejection_reasons, HttpTransport, Transport};
+    use crate::payload::TreblleData;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Request that reached the endpoint stand-in
+    pub(crate) struct Received {
+        pub path: String,
+        pub api_key: String,
+        pub body: serde_json::Value,
+    }
+
+    /// Endpoint stand-in that accepts `count` JSON requests, on as many connections as
+    /// the client makes, responds to them with `200 OK` and returns them
+    pub(crate) fn endpoint(count: usize) -> (String, std::thread::JoinHandle<Vec<Received>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle = std::thread::spawn(move || {
+            let mut requests = vec![];
+            while requests.len() < count {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                while requests.len() < count {
+                    let mut request_line = String::new();
+                    if reader.read_line(&mut request_line).unwrap() == 0 {
+                        break;
+                    }
+
+                    let (mut length, mut api_key) = (0, String::new());
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        match line.split_once(':') {
+                            Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                                length = value.trim().parse().unwrap()
+                            }
+                            Some((name, value)) if name.eq_ignore_ascii_case("x-api-key") => {
+                                api_key = value.trim().to_string()
+                            }
+                            _ => {}
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    reader
+                        .get_mut()
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}")
+                        .unwrap();
+
+                    requests.push(Received {
+                        path: request_line.split(' ').nth(1).unwrap().to_string(),
+                        api_key,
+                        body: serde_json::from_slice(&body).unwrap(),
+                    });
+                }
+            }
+
+            requests
+        });
+
+        (endpoint, handle)
+    }
+
+    #[actix_rt::test]
+    async fn spreads_payloads_across_endpoints() {
+        let (first, first_requests) = endpoint(1);
+        let (second, second_requests) = endpoint(1);
+        let transport = HttpTransport::new()
+            .unwrap()
+            .endpoints(vec![format!("{}/ingest", first), second]);
+
+        for project_id in ["first", "second"] {
+            let payload = TreblleData::new("api_key".to_string(), project_id.to_string());
+            transport.send(&payload).await.unwrap();
+        }
+
+        let first_requests = first_requests.join().unwrap();
+        assert_eq!(first_requests[0].path, "/ingest");
+        assert_eq!(first_requests[0].body["project_id"], "first");
+        assert_eq!(
+            second_requests.join().unwrap()[0].body["project_id"],
+            "second"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn sends_batches_one_payload_at_a_time() {
+        let (endpoint, requests) = endpoint(2);
+        let payloads = [
+            TreblleData::new("first_key".to_string(), "first".to_string()),
+            TreblleData::new("second_key".to_string(), "second".to_string()),
+        ];
+
+        HttpTransport::new()
+            .unwrap()
+            .endpoints(vec![endpoint])
+            .send_batch(&payloads)
+            .await
+            .unwrap();
+
+        let requests = requests.join().unwrap();
+        assert_eq!(requests[0].api_key, "first_key");
+        assert_eq!(requests[0].body["project_id"], "first");
+        assert_eq!(requests[1].api_key, "second_key");
+        assert_eq!(requests[1].body["project_id"], "second");
+    }
+
+    #[test]
+    fn extracts_rejection_reasons() {
+        assert_eq!(
+            rejection_reasons(
+                r#"{"message":"Invalid payload","errors":{"api_key":["is required"]}}"#
+            ),
+            vec!["is required".to_string(), "Invalid payload".to_string()]
+        );
+        assert_eq!(
+            rejection_reasons("Bad Gateway"),
+            vec!["Bad Gateway".to_string()]
+        );
+        assert!(rejection_reasons("").is_empty());
+    }
+}
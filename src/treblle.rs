@@ -1,9 +1,37 @@
+use std::sync::{Arc, OnceLock};
+
+use crate::breaker::CircuitBreaker;
+use crate::capture::CaptureConfig;
+use crate::compression::Compression;
+use crate::delivery::{Batching, DeliveryConfig, DeliveryStats, Dispatcher, DropPolicy, Retry};
+use crate::error::{report, ErrorHandler, TreblleError};
+use crate::handle::TreblleHandle;
+use crate::sampling::{Sampler, Sampling};
+use crate::sink::{Destination, Sink};
+use crate::spool::Spool;
+use crate::transport::{
+    HttpClientConfig, HttpTransport, Misconfigured, Region, Transport, TransportError,
+    DEFAULT_ENDPOINT,
+};
+
+#[derive(Clone)]
 pub struct Treblle {
     pub(crate) project_id: String,
     pub(crate) api_key: String,
     pub(crate) debug: bool,
     pub(crate) masking_fields: Vec<String>,
     pub(crate) ignored_routes: Vec<String>,
+    pub(crate) capture: CaptureConfig,
+    pub(crate) endpoints: Vec<String>,
+    pub(crate) transport: Option<Arc<dyn Transport>>,
+    pub(crate) compression: Option<(Compression, usize)>,
+    pub(crate) http_client: Option<reqwest::Client>,
+    pub(crate) http_client_config: HttpClientConfig,
+    pub(crate) delivery: DeliveryConfig,
+    pub(crate) on_error: Option<ErrorHandler>,
+    pub(crate) sampler: Option<Arc<Sampler>>,
+    pub(crate) sinks: Vec<Sink>,
+    pub(crate) dispatcher: Arc<OnceLock<Arc<Dispatcher>>>,
 }
 
 impl Treblle {
@@ -24,26 +52,28 @@ impl Treblle {
             project_id,
             api_key,
             debug: false,
-            masking_fields: vec![
-                "password".to_string(),
-                "pwd".to_string(),
-                "secret".to_string(),
-                "password_confirmation".to_string(),
-                "passwordConfirmation".to_string(),
-                "cc".to_string(),
-                "card_number".to_string(),
-                "cardNumber".to_string(),
-                "ccv".to_string(),
-                "ssn".to_string(),
-                "credit_score".to_string(),
-                "creditScore".to_string(),
-            ],
+            masking_fields: default_masking_fields(),
             ignored_routes: vec![],
+            capture: CaptureConfig::default(),
+            endpoints: vec![DEFAULT_ENDPOINT.to_string()],
+            transport: None,
+            compression: None,
+            http_client: None,
+            http_client_config: HttpClientConfig::default(),
+            delivery: DeliveryConfig::default(),
+            on_error: None,
+            sampler: None,
+            sinks: vec![],
+            dispatcher: Arc::new(OnceLock::new()),
         }
     }
 
     /// Turn on the debug mode
     ///
+    /// Payloads are logged and sent right away instead of being queued, and any failure
+    /// to deliver them, including the reasons Treblle.com gave for rejecting them, is
+    /// logged as an error and passed to the [`Treblle::on_error`] callback.
+    ///
     /// WARNING: Turning this option ON can slow down your requests by 10 fold sometimes because
     /// we are waiting for the response from Treblle.com.
     ///
@@ -103,6 +133,10 @@ impl Treblle {
     /// - "credit_score"
     /// - "creditScore"
     ///
+    /// Bodies that couldn't be parsed can't be masked field by field, they are replaced
+    /// with `******` as a whole when they have one of the fields as a key, like
+    /// `"password":` or `password=`, which is reported to [`Treblle::on_error`].
+    ///
     /// ```rust,ignore
     /// HttpServer::new(|| {
     ///     App::new()
@@ -150,4 +184,635 @@ impl Treblle {
         self.ignored_routes.append(&mut routes);
         self
     }
+
+    /// Capture request bodies of more content types, by default only JSON bodies,
+    /// `application/json` and the types with the `+json` suffix, urlencoded and multipart
+    /// forms are captured. Parameters like `charset` are ignored when matching.
+    ///
+    /// Urlencoded forms are captured as JSON objects where repeated keys become arrays,
+    /// so they are masked like JSON bodies. Multipart forms are captured only as the
+    /// names, content types, filenames and sizes of their parts, see
+    /// [`Treblle::multipart_text_fields`].
+    ///
+    /// Content types are either exact media types like `text/plain`, wildcards like
+    /// `text/*`, or structured suffixes like `+xml`.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .add_content_types(vec!["text/plain".to_string(), "+xml".to_string()])
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn add_content_types(mut self, mut content_types: Vec<String>) -> Treblle {
+        self.capture.content_types.append(&mut content_types);
+        self
+    }
+
+    /// Remove the content types of request bodies that are captured, including the
+    /// default ones
+    pub fn clear_content_types(mut self) -> Treblle {
+        self.capture.content_types.clear();
+        self
+    }
+
+    /// Capture the values of multipart text fields, parts without a filename, that
+    /// aren't longer than `max_bytes`. Values are masked like the fields of JSON bodies.
+    ///
+    /// Multipart bodies are never buffered or changed, the middleware only looks at the
+    /// bytes while your handler reads them, so fields are captured only as far as the
+    /// handler read the body.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .multipart_text_fields(256)
+    ///         )
+    ///         .route("/upload", web::post().to(upload))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn multipart_text_fields(mut self, max_bytes: usize) -> Treblle {
+        self.capture.multipart_text = max_bytes;
+        self
+    }
+
+    /// Capture at most `max_bytes` of every request body, 1MB by default.
+    ///
+    /// Request bodies aren't buffered before your handler runs, the middleware copies
+    /// the chunks while the handler reads them and parses the copy after the response.
+    /// When the handler doesn't read the whole body, the part of the rest that already
+    /// arrived is copied, up to `max_bytes`, but the middleware never waits for the client
+    /// to send more of it. Bodies longer than the limit or not read to the end are
+    /// recorded as text with `truncated` and the `size` that was read.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .request_body_limit(64 * 1024)
+    ///         )
+    ///         .route("/users", web::post().to(create_user))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn request_body_limit(mut self, max_bytes: usize) -> Treblle {
+        self.capture.max_bytes = max_bytes;
+        self
+    }
+
+    /// Capture at most `max_bytes` of every response body that streams, like
+    /// `HttpResponse::streaming` or server-sent events, 16KB by default.
+    ///
+    /// Those bodies are sent unchanged while the middleware copies them and counts their
+    /// size, the payload is delivered once the body ended or the client went away.
+    /// Only JSON and `text/*` bodies are copied, files and other binary bodies are
+    /// recorded with their size only. Bodies longer than the limit are recorded as text
+    /// cut off at the limit.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .response_body_limit(16 * 1024)
+    ///         )
+    ///         .route("/events", web::get().to(events))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn response_body_limit(mut self, max_bytes: usize) -> Treblle {
+        self.capture.response_max_bytes = max_bytes;
+        self
+    }
+
+    /// Send payloads to a different endpoint, for example a local stand-in during
+    /// integration tests or a corporate egress relay.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .endpoint("http://127.0.0.1:9999")
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn endpoint<E: Into<String>>(mut self, endpoint: E) -> Treblle {
+        self.endpoints = vec![endpoint.into()];
+        self
+    }
+
+    /// Spread payloads across multiple endpoints in a round robin fashion
+    ///
+    /// Passing an empty vector keeps the endpoints that were set before.
+    pub fn endpoints(mut self, endpoints: Vec<String>) -> Treblle {
+        if !endpoints.is_empty() {
+            self.endpoints = endpoints;
+        }
+        self
+    }
+
+    /// Send payloads to one of the named endpoint presets
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .region(actix_treblle::Region::Global)
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn region(mut self, region: Region) -> Treblle {
+        self.endpoints = region.endpoints();
+        self
+    }
+
+    /// Compress payloads that are at least `min_bytes` big before sending them, this
+    /// needs the `gzip` or `zstd` feature of the crate.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .compression(actix_treblle::Compression::Gzip, 1024)
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn compression(mut self, compression: Compression, min_bytes: usize) -> Treblle {
+        self.compression = Some((compression, min_bytes));
+        self
+    }
+
+    /// Configure timeouts, proxy and TLS of the HTTP client used to send payloads
+    ///
+    /// If the client can't be built with these settings, the error is logged and reported
+    /// to [`Treblle::on_error`], and payloads aren't delivered at all rather than being
+    /// sent without the configured proxy or certificates.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .http_client_config(
+    ///                    actix_treblle::HttpClientConfig::new()
+    ///                        .connect_timeout(std::time::Duration::from_millis(500))
+    ///                        .proxy(reqwest::Proxy::https("http://egress.internal:3128").unwrap())
+    ///                )
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn http_client_config(mut self, config: HttpClientConfig) -> Treblle {
+        self.http_client_config = config;
+        self
+    }
+
+    /// Send payloads with a HTTP client you already have, so the connection pool is
+    /// shared with the rest of your application. Takes precedence over
+    /// [`Treblle::http_client_config`].
+    ///
+    /// Payloads are delivered on a runtime of their own, and the connections it opens stay
+    /// bound to it. That runtime stops once the middleware is dropped or
+    /// [`crate::TreblleHandle::shutdown`] is called, after that requests of your application
+    /// that reuse those pooled connections fail, so don't use a shared client after the
+    /// shutdown.
+    pub fn http_client(mut self, client: reqwest::Client) -> Treblle {
+        self.http_client = Some(client);
+        self
+    }
+
+    /// Deliver payloads with your own transport instead of sending them to Treblle.com,
+    /// configured endpoints are ignored in that case.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .transport(MyRelayTransport::new("https://relay.internal"))
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn transport<T: Transport + 'static>(mut self, transport: T) -> Treblle {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Set how many payloads can wait for delivery at once, default is 1000.
+    ///
+    /// Payloads are delivered by a single background worker, so this caps the memory the
+    /// middleware can use while Treblle.com is slow or unreachable.
+    ///
+    /// The queue is shared by every clone of this `Treblle`, so create it once outside of
+    /// the `HttpServer::new` closure if you want one queue for all of the actix workers.
+    ///
+    /// ```rust,ignore
+    /// let treblle = actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///     .queue_capacity(10_000)
+    ///     .drop_policy(actix_treblle::DropPolicy::DropOldest);
+    ///
+    /// HttpServer::new(move || {
+    ///     App::new()
+    ///         .wrap(treblle.clone())
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn queue_capacity(mut self, capacity: usize) -> Treblle {
+        self.delivery.capacity = capacity;
+        self
+    }
+
+    /// Set what happens with new payloads when the delivery queue is full, by default
+    /// the newest payload is dropped.
+    pub fn drop_policy(mut self, policy: DropPolicy) -> Treblle {
+        self.delivery.policy = policy;
+        self
+    }
+
+    /// Group payloads into batches that are handed to the transport at once. A batch is
+    /// sent once it has `max_payloads` payloads, once its payloads take `max_bytes`
+    /// bytes, or once its first payload waited for `linger`.
+    ///
+    /// Treblle.com takes a single payload per request, so with the default transport
+    /// batching does not reduce the number of requests to Treblle.com, the payloads of a
+    /// batch are still posted one by one. Custom transports can upload a whole batch at
+    /// once by overriding [`crate::Transport::send_batch`].
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .batching(actix_treblle::Batching {
+    ///                    max_payloads: 50,
+    ///                    max_bytes: 512 * 1024,
+    ///                    linger: std::time::Duration::from_secs(2),
+    ///                })
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn batching(mut self, batching: Batching) -> Treblle {
+        self.delivery.batching = Some(batching);
+        self
+    }
+
+    /// Retry deliveries that failed because of connection failures, timeouts, 5xx or
+    /// 429 responses, with exponential backoff and jitter between the attempts.
+    ///
+    /// Retries are done by the background worker, so they never slow down your requests,
+    /// but payloads will wait in the queue while the worker is backing off.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .retry(actix_treblle::Retry {
+    ///                    max_attempts: 5,
+    ///                    ..Default::default()
+    ///                })
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn retry(mut self, retry: Retry) -> Treblle {
+        self.delivery.retry = Some(retry);
+        self
+    }
+
+    /// Write payloads to disk when the endpoint can't be reached and send them once it
+    /// recovers. Spooled payloads survive a restart of the application.
+    ///
+    /// Build the middleware once and clone it into the workers, so they share a single
+    /// spool. A directory can be used by one spool at a time, opening it again fails and
+    /// is reported to [`Treblle::on_error`].
+    ///
+    /// ```rust,ignore
+    /// let treblle = actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///     .spool(actix_treblle::Spool::new("/var/spool/treblle"));
+    ///
+    /// HttpServer::new(move || {
+    ///     App::new()
+    ///         .wrap(treblle.clone())
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn spool(mut self, spool: Spool) -> Treblle {
+        self.delivery.spool = Some(spool);
+        self
+    }
+
+    /// Stop delivering payloads for a while when the endpoint keeps failing, see
+    /// [`CircuitBreaker`] for details. While the circuit is open requests aren't even
+    /// captured, unless there is a spool to write them to.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .circuit_breaker(actix_treblle::CircuitBreaker {
+    ///                    failure_threshold: 3,
+    ///                    cooldown: std::time::Duration::from_secs(60),
+    ///                })
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Treblle {
+        self.delivery.breaker = Some(breaker);
+        self
+    }
+
+    /// Capture only a share of the requests, see [`Sampling`] for the per route rates and
+    /// the rules for responses that are always kept.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .sampling(
+    ///                    actix_treblle::Sampling::new(0.1)
+    ///                        .route("/users/{user_id}", 0.5)
+    ///                        .keep(actix_treblle::Keep::ServerErrors)
+    ///                )
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn sampling(mut self, sampling: Sampling) -> Treblle {
+        self.sampler = Some(Arc::new(Sampler::new(sampling)));
+        self
+    }
+
+    /// Deliver the payloads to another [`Sink`] as well, with its own masking, sampling
+    /// and queue. A sink that fails doesn't affect the delivery to Treblle.com or to the
+    /// other sinks.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .sink(actix_treblle::Sink::new("audit", MyTransport::default()))
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn sink(mut self, sink: Sink) -> Treblle {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Counters of queued, dropped, delivered and failed payloads
+    pub fn delivery_stats(&self) -> DeliveryStats {
+        self.dispatcher
+            .get()
+            .map(|dispatcher| dispatcher.stats())
+            .unwrap_or_default()
+    }
+
+    /// Get notified about the failures of the SDK, for example to wire them into your
+    /// own alerting: requests that couldn't be captured, bodies that had to be masked
+    /// completely, payloads that couldn't be serialized, delivered or spooled.
+    ///
+    /// The callback is called on the thread where the failure happened, request handler
+    /// or the background delivery worker, so keep it short.
+    ///
+    /// ```rust,ignore
+    /// HttpServer::new(|| {
+    ///     App::new()
+    ///         .wrap(
+    ///             actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string())
+    ///                .debug()
+    ///                .on_error(|e| log::warn!("{}", e))
+    ///         )
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await
+    /// ```
+    pub fn on_error<F>(mut self, handler: F) -> Treblle
+    where
+        F: Fn(&TreblleError) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Arc::new(handler));
+        self
+    }
+
+    /// Handle that lets you flush the pending payloads when your application shuts down
+    ///
+    /// ```rust,ignore
+    /// let treblle = actix_treblle::Treblle::new("project_id".to_string(), "api_key".to_string());
+    /// let handle = treblle.handle();
+    ///
+    /// HttpServer::new(move || {
+    ///     App::new()
+    ///         .wrap(treblle.clone())
+    ///         .route("/hello", web::get().to(|| async { "Hello World!" }))
+    /// })
+    /// .bind(("127.0.0.1", 8080))?
+    /// .run()
+    /// .await?;
+    ///
+    /// handle.flush(std::time::Duration::from_secs(5)).await;
+    /// ```
+    pub fn handle(&self) -> TreblleHandle {
+        TreblleHandle {
+            dispatcher: self.dispatcher.clone(),
+            sinks: self
+                .sinks
+                .iter()
+                .map(|sink| sink.dispatcher.clone())
+                .collect(),
+        }
+    }
+
+    /// Transport that payloads will be delivered with
+    pub(crate) fn build_transport(&self) -> Arc<dyn Transport> {
+        match (&self.transport, &self.http_client) {
+            (Some(transport), _) => transport.clone(),
+            (None, Some(client)) => self.http_transport(Ok(client.clone())),
+            (None, None) => self.http_transport(self.http_client_config.build()),
+        }
+    }
+
+    /// Default transport with the given client. A client that couldn't be configured is
+    /// reported and nothing is delivered, rather than sending payloads without the
+    /// configured proxy or certificates.
+    fn http_transport(&self, client: reqwest::Result<reqwest::Client>) -> Arc<dyn Transport> {
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                let e = TransportError::Http(e);
+                log::error!("Treblle HTTP client couldn't be configured: {}", e);
+                let message = e.to_string();
+                report(self.on_error.as_ref(), TreblleError::Delivery(e));
+
+                return Arc::new(Misconfigured(message));
+            }
+        };
+
+        let mut transport = HttpTransport::with_client(client).endpoints(self.endpoints.clone());
+        if let Some((compression, min_bytes)) = self.compression {
+            transport = transport.compression(compression, min_bytes);
+        }
+
+        Arc::new(transport)
+    }
+
+    /// Dispatcher shared by all the clones, the worker is started on first use
+    pub(crate) fn dispatcher(&self) -> Arc<Dispatcher> {
+        self.dispatcher
+            .get_or_init(|| {
+                Arc::new(Dispatcher::start(
+                    self.build_transport(),
+                    self.delivery.clone(),
+                    self.on_error.clone(),
+                ))
+            })
+            .clone()
+    }
+
+    /// Treblle.com followed by the additional sinks
+    pub(crate) fn destinations(&self) -> Vec<Destination> {
+        let mut destinations = vec![Destination {
+            masking_fields: self.masking_fields.clone(),
+            sampler: self.sampler.clone(),
+            dispatcher: self.dispatcher(),
+        }];
+        for sink in &self.sinks {
+            destinations.push(Destination {
+                masking_fields: sink.masking_fields.clone(),
+                sampler: sink.sampler.clone(),
+                dispatcher: sink.dispatcher(self.on_error.clone()),
+            });
+        }
+
+        destinations
+    }
+}
+
+/// Fields that are masked unless they are cleared
+pub(crate) fn default_masking_fields() -> Vec<String> {
+    vec![
+        "password".to_string(),
+        "pwd".to_string(),
+        "secret".to_string(),
+        "password_confirmation".to_string(),
+        "passwordConfirmation".to_string(),
+        "cc".to_string(),
+        "card_number".to_string(),
+        "cardNumber".to_string(),
+        "ccv".to_string(),
+        "ssn".to_string(),
+        "credit_score".to_string(),
+        "creditScore".to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Region, Treblle};
+    use crate::TreblleData;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn configures_endpoints() {
+        let treblle = || Treblle::new("project_id".to_string(), "api_key".to_string());
+        assert_eq!(
+            treblle().endpoints,
+            vec![crate::DEFAULT_ENDPOINT.to_string()]
+        );
+
+        let treblle = treblle().endpoint("http://127.0.0.1:9999");
+        assert_eq!(treblle.endpoints, vec!["http://127.0.0.1:9999".to_string()]);
+
+        // Empty vector keeps the endpoints that were set before
+        let treblle = treblle.endpoints(vec![]);
+        assert_eq!(treblle.endpoints, vec!["http://127.0.0.1:9999".to_string()]);
+
+        let treblle = treblle.region(Region::Global);
+        assert_eq!(treblle.endpoints, Region::Global.endpoints());
+        assert_eq!(treblle.endpoints.len(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn does_not_deliver_with_a_misconfigured_client() {
+        let errors = Arc::new(Mutex::new(vec![]));
+        let reported = errors.clone();
+        let treblle = Treblle::new("project_id".to_string(), "api_key".to_string())
+            .on_error(move |e| reported.lock().unwrap().push(e.to_string()));
+
+        // Building a request with an invalid URL is the simplest way to get a reqwest error
+        let e = reqwest::Client::new().get("not a url").build().unwrap_err();
+        let transport = treblle.http_transport(Err(e));
+
+        assert_eq!(errors.lock().unwrap().len(), 1);
+        let payload = TreblleData::new("api_key".to_string(), "project_id".to_string());
+        let e = transport.send(&payload).await.unwrap_err();
+        assert!(e.to_string().contains("HTTP client couldn't be configured"));
+    }
 }